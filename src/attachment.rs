@@ -0,0 +1,54 @@
+//! Documents attached to records
+//!
+//! A draft can hold documents (eg: a scan of an ID card or a delivery slip). Their content is
+//! stored apart, the record only lists them. They can't be added nor removed once the draft is
+//! submitted.
+
+use tokio_stream::{Stream, StreamExt};
+
+use crate::{error::Error, storage::ByteStream};
+
+/// Maximum size of an attachment
+const MAX_SIZE: usize = 16 * 1024 * 1024;
+const MAX_FILENAME: usize = 255;
+
+/// Check the description of an attachment
+pub(crate) fn check(filename: &str, content_type: &str) -> Result<(), Error> {
+    if filename.is_empty() || filename.len() > MAX_FILENAME {
+        return Err(Error::invalid_argument(
+            "filename",
+            format!("a filename is 1 to {} bytes", MAX_FILENAME),
+        ));
+    }
+
+    if !content_type.contains('/') {
+        return Err(Error::invalid_argument(
+            "content_type",
+            "a media type is required (eg: image/jpeg)",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Content of an upload, ending with an error once it exceeds the maximum size
+pub(crate) fn content<S>(chunks: S) -> ByteStream
+where
+    S: Stream<Item = Result<Vec<u8>, Error>> + Send + 'static,
+{
+    let mut length = 0;
+
+    Box::pin(chunks.map(move |chunk| {
+        let chunk = chunk?;
+        length += chunk.len();
+
+        if length > MAX_SIZE {
+            Err(Error::invalid_argument(
+                "data",
+                format!("an attachment is at most {} bytes", MAX_SIZE),
+            ))
+        } else {
+            Ok(chunk)
+        }
+    }))
+}
@@ -0,0 +1,128 @@
+//! Audit trail
+//!
+//! Every call of a mutating RPC is kept with its outcome, failed calls included:
+//! who (caller), what (RPC, record and digest of the request), when and the grpc status.
+//! Entries are only appended, the service never updates nor deletes them.
+//!
+//! A call rejected by the auth is audited before its request is read: without record nor digest.
+
+use chrono::Utc;
+use prost::Message;
+use sha2::{Digest, Sha256};
+use tonic::{Response, Status};
+
+use crate::{auth::Caller, mongodb::AuditEntry, storage::Origin};
+
+/// Caller of a request without valid credentials
+static UNAUTHENTICATED: &str = "unauthenticated";
+
+/// RPCs audited with their outcome
+static MUTATING: &[&str] = &[
+    "NewDraft",
+    "UpdateDraft",
+    "DeleteDraft",
+    "UploadAttachment",
+    "DeleteAttachment",
+    "SubmitDraft",
+    "CollectClientInside",
+    "CollectClientSignature",
+    "CollectClientOutside",
+    "CollectPqrsSignature",
+    "ReturnClientInside",
+    "ReturnClientSignature",
+    "ReturnClientOutside",
+    "ReturnPqrsSignature",
+    "RegisterSignerKey",
+    "Complete",
+];
+
+/// A mutating RPC call, audited with its outcome
+pub(crate) struct Call {
+    pub(crate) origin: Origin,
+    record_id: Option<String>,
+    digest: String,
+}
+
+impl Call {
+    /// Call on a record, none if the id is empty
+    pub(crate) fn new(origin: Origin, payload: &impl Message, record_id: &str) -> Self {
+        let digest = hex::encode(Sha256::digest(payload.encode_to_vec()));
+
+        Self {
+            origin,
+            record_id: (!record_id.is_empty()).then(|| record_id.to_owned()),
+            digest,
+        }
+    }
+
+    /// Call rejected by the auth, none if the RPC isn't audited
+    pub(crate) fn rejected(rpc: &str, caller: Option<&Caller>) -> Option<Self> {
+        let rpc = MUTATING.iter().find(|name| **name == rpc)?;
+        let caller = caller.map_or(UNAUTHENTICATED, |caller| caller.id.as_str());
+
+        Some(Self {
+            origin: Origin {
+                caller: caller.to_owned(),
+                rpc,
+            },
+            record_id: None,
+            digest: String::new(),
+        })
+    }
+
+    /// Digest of the content streamed by the call, instead of its first message
+    pub(crate) fn on_content(&mut self, digest: String) {
+        self.digest = digest;
+    }
+
+    /// Record created by the call
+    pub(crate) fn on_record(&mut self, record_id: String) {
+        self.record_id = Some(record_id);
+    }
+
+    pub(crate) fn entry<T>(self, outcome: &Result<Response<T>, Status>) -> AuditEntry {
+        let (code, message) = match outcome {
+            Ok(_) => (0, String::new()),
+            Err(status) => (status.code() as i32, status.message().to_owned()),
+        };
+
+        AuditEntry {
+            id: None,
+            time: Utc::now().timestamp(),
+            caller: self.origin.caller,
+            rpc: self.origin.rpc.to_owned(),
+            record_id: self.record_id,
+            code,
+            message,
+            digest: self.digest,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mutating_rpcs_are_rpcs() {
+        let proto = include_str!("../proto/register.proto");
+
+        for rpc in MUTATING {
+            assert!(
+                proto.contains(&format!("rpc {}(", rpc)),
+                "{} isn't an rpc",
+                rpc
+            );
+        }
+    }
+
+    #[test]
+    fn rejected_reads_are_not_audited() {
+        assert!(Call::rejected("Search", None).is_none());
+        assert!(Call::rejected("Unknown", None).is_none());
+
+        let call = Call::rejected("Complete", None).expect("complete audited");
+        assert_eq!(call.origin.caller, UNAUTHENTICATED);
+        assert_eq!(call.origin.rpc, "Complete");
+    }
+}
@@ -1,42 +1,454 @@
-//! Authentication
+//! Authentication and authorization
 //!
-//! Used to intercept request and validate token.
+//! Wraps the service to validate the token or the client certificate, and check its roles allow
+//! the RPC before the handler runs. The caller identity is added to the request extensions, with
+//! the claims of a JWT bearer token. A rejected call of a mutating RPC is audited here, its
+//! handler never runs.
 
+mod certificate;
+mod jwt;
+
+use std::{collections::HashMap, sync::Arc};
+
+use futures_util::future::{BoxFuture, Either};
+use sha2::{Digest, Sha256};
 use tonic::{
-    metadata::{errors::InvalidMetadataValue, Ascii, MetadataValue},
-    Request, Status,
+    body::BoxBody,
+    codegen::{Context, Poll, Service},
+    server::NamedService,
+    Status,
+};
+
+use crate::{
+    audit::Call,
+    config::{ClientConfig, TokenConfig},
+    permission::{self, Role},
+    storage::Storage,
 };
 
+pub(crate) use self::jwt::{Claims, Jwt};
+
 static AUTH_KEY: &str = "apikey";
+static BEARER: &str = "Bearer ";
+
+static ANONYMOUS: &str = "anonymous";
+
+/// Identity of the caller of a request
+#[derive(Clone, Debug)]
+pub(crate) struct Caller {
+    /// Kept in the history and the audit trail (eg: `jwt:<sub>`)
+    pub(crate) id: String,
+    /// Name of the person, if known by the credentials
+    pub(crate) name: Option<String>,
+}
+
+impl Caller {
+    /// Caller without credentials, when auth is disabled
+    pub(crate) fn anonymous() -> Self {
+        Caller {
+            id: ANONYMOUS.to_owned(),
+            name: None,
+        }
+    }
+
+    pub(crate) fn is_anonymous(&self) -> bool {
+        self.id == ANONYMOUS
+    }
+
+    /// Caller identified by a token, the token itself is never exposed
+    fn from_token(token: &str, name: Option<String>) -> Self {
+        let digest = Sha256::digest(token.as_bytes());
+
+        Caller {
+            id: format!("apikey:{}", hex::encode(&digest[..4])),
+            name,
+        }
+    }
+
+    /// Caller identified by its client certificate
+    fn from_certificate(identity: &str, name: Option<String>) -> Self {
+        Caller {
+            id: format!("cert:{}", identity),
+            name,
+        }
+    }
+
+    /// Caller identified by the subject of a JWT
+    fn from_claims(claims: &Claims, name_claim: &str) -> Self {
+        Caller {
+            id: format!("jwt:{}", claims.sub),
+            name: claims.name(name_claim),
+        }
+    }
+}
+
+/// Credentials of an api token or a client certificate
+struct Credentials {
+    roles: Vec<Role>,
+    name: Option<String>,
+}
 
 pub(crate) struct Auth {
-    tokens: Vec<MetadataValue<Ascii>>,
+    tokens: HashMap<String, Credentials>,
+    /// By client certificate identity
+    clients: HashMap<String, Credentials>,
+    jwt: Option<Arc<Jwt>>,
 }
 
 impl Auth {
-    pub(crate) fn new(config: Vec<String>) -> Result<Self, InvalidMetadataValue> {
-        let mut tokens: Vec<MetadataValue<Ascii>> = Vec::with_capacity(config.len());
+    pub(crate) fn new(
+        config: Vec<TokenConfig>,
+        clients: Vec<ClientConfig>,
+        jwt: Option<Arc<Jwt>>,
+    ) -> Self {
+        let tokens: HashMap<String, Credentials> = config
+            .into_iter()
+            .map(|token| match token {
+                TokenConfig::Plain(token) => (
+                    token,
+                    Credentials {
+                        roles: vec![Role::Admin],
+                        name: None,
+                    },
+                ),
+                TokenConfig::WithRoles { token, roles, name } => {
+                    (token, Credentials { roles, name })
+                }
+            })
+            .collect();
 
-        for token in config {
-            tokens.push(token.parse()?);
+        let clients: HashMap<String, Credentials> = clients
+            .into_iter()
+            .map(|client| {
+                (
+                    client.identity,
+                    Credentials {
+                        roles: client.roles,
+                        name: client.name,
+                    },
+                )
+            })
+            .collect();
+
+        tracing::info!(
+            "use auth: {}",
+            !tokens.is_empty() || !clients.is_empty() || jwt.is_some()
+        );
+
+        Self {
+            tokens,
+            clients,
+            jwt,
         }
+    }
+
+    /// Identify the caller of a request, and check its roles allow the RPC
+    ///
+    /// The caller is added to the request extensions, denied or not.
+    // the status is the http response of a rejected request
+    #[allow(clippy::result_large_err)]
+    pub(crate) fn check_auth<B>(&self, req: &mut http::Request<B>) -> Result<(), Status> {
+        if self.tokens.is_empty() && self.clients.is_empty() && self.jwt.is_none() {
+            // every RPC is allowed, a verified certificate still identifies the caller
+            let caller = certificate::peer_identity(req)
+                .map_or_else(Caller::anonymous, |identity| {
+                    Caller::from_certificate(&identity, None)
+                });
+            req.extensions_mut().insert(caller);
+            return Ok(());
+        }
+
+        let (caller, roles) = self.authenticate(req)?;
+        let rpc = rpc(req);
+
+        if !permission::allows(&roles, rpc) {
+            let roles = roles
+                .iter()
+                .map(Role::to_string)
+                .collect::<Vec<_>>()
+                .join(", ");
 
-        tracing::info!("use auth: {}", !tokens.is_empty());
+            tracing::warn!("{} denied to {} ({})", rpc, caller.id, roles);
+            let status =
+                Status::permission_denied(format!("{} can't be called with roles: {}", rpc, roles));
+            req.extensions_mut().insert(caller);
 
-        Ok(Self { tokens })
+            return Err(status);
+        }
+
+        req.extensions_mut().insert(caller);
+
+        Ok(())
     }
 
-    pub(crate) fn check_auth(&self, req: Request<()>) -> Result<Request<()>, Status> {
-        let token = req.metadata().get(AUTH_KEY);
+    /// Identity and roles of the caller, from its bearer token, its api token or its client
+    /// certificate
+    #[allow(clippy::result_large_err)]
+    fn authenticate<B>(&self, req: &mut http::Request<B>) -> Result<(Caller, Vec<Role>), Status> {
+        let authorization = req
+            .headers()
+            .get(http::header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix(BEARER));
+
+        if let Some(token) = authorization {
+            let Some(jwt) = &self.jwt else {
+                tracing::warn!("bearer token without jwt configuration");
+                return Err(Status::unauthenticated("Bearer tokens are not accepted"));
+            };
+
+            let claims = jwt.verify(token)?;
+            let caller = Caller::from_claims(&claims, jwt.name_claim());
+            let roles = claims.roles(jwt.roles_claim());
+
+            req.extensions_mut().insert(claims);
 
-        match token {
-            None if self.tokens.is_empty() => Ok(req),
-            Some(_) if self.tokens.is_empty() => Ok(req),
-            Some(token) if self.tokens.contains(token) => Ok(req),
-            _ => {
+            return Ok((caller, roles));
+        }
+
+        if let Some(token) = req.headers().get(AUTH_KEY) {
+            let token = token.to_str().ok();
+
+            return match token.and_then(|token| self.tokens.get_key_value(token)) {
+                Some((token, credentials)) => Ok((
+                    Caller::from_token(token, credentials.name.clone()),
+                    credentials.roles.clone(),
+                )),
+                None => {
+                    tracing::warn!("unauthenticated request");
+                    Err(Status::unauthenticated("No valid auth token"))
+                }
+            };
+        }
+
+        // verified against the client CA during the handshake
+        match certificate::peer_identity(req) {
+            Some(identity) => match self.clients.get(&identity) {
+                Some(credentials) => Ok((
+                    Caller::from_certificate(&identity, credentials.name.clone()),
+                    credentials.roles.clone(),
+                )),
+                None => {
+                    tracing::warn!("client certificate {} has no role", identity);
+                    Ok((Caller::from_certificate(&identity, None), vec![]))
+                }
+            },
+            None => {
                 tracing::warn!("unauthenticated request");
                 Err(Status::unauthenticated("No valid auth token"))
             }
         }
     }
 }
+
+/// Name of the RPC of a request (eg: `Complete`)
+fn rpc<B>(req: &http::Request<B>) -> &str {
+    // path of a grpc request: /<package>.<service>/<rpc>
+    req.uri().path().rsplit('/').next().unwrap_or_default()
+}
+
+/// A service called only by authorized callers
+#[derive(Clone)]
+pub(crate) struct Authorized<S> {
+    inner: S,
+    auth: Arc<Auth>,
+    /// Audit trail of the rejected calls
+    db: Arc<dyn Storage>,
+}
+
+impl<S> Authorized<S> {
+    pub(crate) fn new(inner: S, auth: Arc<Auth>, db: Arc<dyn Storage>) -> Self {
+        Self { inner, auth, db }
+    }
+}
+
+impl<S, B> Service<http::Request<B>> for Authorized<S>
+where
+    S: Service<http::Request<B>, Response = http::Response<BoxBody>>,
+    S::Error: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Either<S::Future, BoxFuture<'static, Result<Self::Response, Self::Error>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: http::Request<B>) -> Self::Future {
+        match self.auth.check_auth(&mut req) {
+            Ok(()) => Either::Left(self.inner.call(req)),
+            Err(status) => {
+                let call = Call::rejected(rpc(&req), req.extensions().get::<Caller>());
+                let db = self.db.clone();
+
+                // as the handlers do: the status is returned even if the audit trail fails
+                Either::Right(Box::pin(async move {
+                    if let Some(call) = call {
+                        let rpc = call.origin.rpc;
+                        let entry = call.entry::<()>(&Err(status.clone()));
+
+                        if let Err(e) = db.append_audit(entry).await {
+                            tracing::error!("audit of {} not written: {}", rpc, e);
+                        }
+                    }
+
+                    Ok(status.to_http())
+                }))
+            }
+        }
+    }
+}
+
+impl<S: NamedService> NamedService for Authorized<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use futures_util::future::{self, Ready};
+    use tokio_stream::StreamExt;
+    use tonic::Code;
+
+    use super::*;
+    use crate::{
+        chain::Chain,
+        mongodb::AuditEntry,
+        storage::{AuditQuery, Memory},
+    };
+
+    /// Handler answering every authorized call
+    #[derive(Clone)]
+    struct Handler;
+
+    impl<B> Service<http::Request<B>> for Handler {
+        type Response = http::Response<BoxBody>;
+        type Error = Infallible;
+        type Future = Ready<Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _: http::Request<B>) -> Self::Future {
+            future::ready(Ok(http::Response::new(tonic::body::empty_body())))
+        }
+    }
+
+    fn authorized(db: Arc<dyn Storage>) -> Authorized<Handler> {
+        let tokens = vec![TokenConfig::WithRoles {
+            token: "clerk-token".to_owned(),
+            roles: vec![Role::Clerk],
+            name: None,
+        }];
+
+        Authorized::new(Handler, Arc::new(Auth::new(tokens, vec![], None)), db)
+    }
+
+    fn request(rpc: &str, token: Option<&str>) -> http::Request<()> {
+        let mut request = http::Request::builder().uri(format!("/register.Register/{}", rpc));
+        if let Some(token) = token {
+            request = request.header(AUTH_KEY, token);
+        }
+
+        request.body(()).expect("request built")
+    }
+
+    async fn audit(db: &Arc<dyn Storage>) -> Vec<AuditEntry> {
+        let query = AuditQuery {
+            range: None,
+            record_id: None,
+        };
+
+        db.audit(query)
+            .await
+            .expect("audit read")
+            .map(|entry| entry.expect("audit entry"))
+            .collect()
+            .await
+    }
+
+    #[tokio::test]
+    async fn rejected_calls_are_audited() {
+        let db: Arc<dyn Storage> = Arc::new(Memory::new(Chain::new(b"")));
+        let mut service = authorized(db.clone());
+
+        for (rpc, token) in [
+            ("CollectPqrsSignature", Some("clerk-token")),
+            ("DeleteDraft", Some("wrong-token")),
+            ("DeleteDraft", None),
+        ] {
+            let response = service.call(request(rpc, token)).await.expect("response");
+            let status = Status::from_header_map(response.headers()).expect("status");
+            assert_ne!(status.code(), Code::Ok);
+        }
+
+        let entries = audit(&db).await;
+        let audited: Vec<_> = entries
+            .iter()
+            .map(|entry| (entry.caller.as_str(), entry.rpc.as_str(), entry.code))
+            .collect();
+        let clerk = Caller::from_token("clerk-token", None);
+
+        assert_eq!(
+            audited,
+            [
+                (
+                    clerk.id.as_str(),
+                    "CollectPqrsSignature",
+                    Code::PermissionDenied as i32
+                ),
+                (
+                    "unauthenticated",
+                    "DeleteDraft",
+                    Code::Unauthenticated as i32
+                ),
+                (
+                    "unauthenticated",
+                    "DeleteDraft",
+                    Code::Unauthenticated as i32
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn rejected_reads_are_not_audited() {
+        let db: Arc<dyn Storage> = Arc::new(Memory::new(Chain::new(b"")));
+        let mut service = authorized(db.clone());
+
+        service
+            .call(request("QueryAudit", Some("clerk-token")))
+            .await
+            .expect("response");
+
+        assert!(audit(&db).await.is_empty());
+    }
+
+    #[test]
+    fn caller_without_credentials_is_anonymous() {
+        let auth = Auth::new(vec![], vec![], None);
+        let mut request = request("Complete", None);
+
+        auth.check_auth(&mut request).expect("call allowed");
+
+        let caller = request.extensions().get::<Caller>().expect("caller");
+        assert!(caller.is_anonymous());
+    }
+
+    #[tokio::test]
+    async fn allowed_calls_reach_the_handler() {
+        let db: Arc<dyn Storage> = Arc::new(Memory::new(Chain::new(b"")));
+        let mut service = authorized(db.clone());
+
+        let response = service
+            .call(request("NewDraft", Some("clerk-token")))
+            .await
+            .expect("response");
+
+        assert!(Status::from_header_map(response.headers()).is_none());
+        assert!(audit(&db).await.is_empty());
+    }
+}
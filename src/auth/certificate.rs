@@ -0,0 +1,127 @@
+//! Client certificates
+//!
+//! With mutual TLS, the client certificate is verified against the configured CA bundle during
+//! the handshake. Its identity is the first DNS, email or URI subject alternative name, or the
+//! subject common name of a certificate without one.
+
+use tonic::transport::{
+    server::{TcpConnectInfo, TlsConnectInfo},
+    Certificate,
+};
+use x509_parser::{certificate::X509Certificate, extensions::GeneralName, prelude::FromDer};
+
+/// Identity of the client certificate of a request, if the client presented one
+pub(crate) fn peer_identity<B>(req: &http::Request<B>) -> Option<String> {
+    let certs = req
+        .extensions()
+        .get::<TlsConnectInfo<TcpConnectInfo>>()
+        .and_then(TlsConnectInfo::peer_certs)?;
+
+    // the end-entity certificate comes first
+    identity(certs.first()?)
+}
+
+fn identity(cert: &Certificate) -> Option<String> {
+    let (_, cert) = X509Certificate::from_der(cert.get_ref())
+        .map_err(|e| tracing::warn!("invalid client certificate: {}", e))
+        .ok()?;
+
+    let alternative_name = cert
+        .subject_alternative_name()
+        .ok()
+        .flatten()
+        .and_then(|extension| {
+            extension
+                .value
+                .general_names
+                .iter()
+                .find_map(|name| match name {
+                    GeneralName::DNSName(name)
+                    | GeneralName::RFC822Name(name)
+                    | GeneralName::URI(name) => Some(name.to_string()),
+                    _ => None,
+                })
+        });
+
+    alternative_name.or_else(|| {
+        cert.subject()
+            .iter_common_name()
+            .find_map(|name| name.as_str().ok())
+            .map(str::to_owned)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // self-signed ed25519 certificates:
+    // openssl req -x509 -key key.pem -days 36500 -subj <subject> [-addext subjectAltName=<san>]
+
+    /// CN=kiosk-cn, SAN DNS:kiosk-1.example.org
+    const WITH_SAN: &str = "-----BEGIN CERTIFICATE-----
+MIIBXTCCAQ+gAwIBAgIUFKWQTdYUYV3GAywbfr9FqjaHFzowBQYDK2VwMBMxETAP
+BgNVBAMMCGtpb3NrLWNuMCAXDTI2MTAxODA1NTIxMVoYDzIxMjYwOTI0MDU1MjEx
+WjATMREwDwYDVQQDDAhraW9zay1jbjAqMAUGAytlcAMhAI8E3FIrE5d7ilTirlez
+r0fBkckBxoQejmxa5E5aJl2+o3MwcTAdBgNVHQ4EFgQUN2vZwXDsYRGzWCN3loNB
+8EiQvdcwHwYDVR0jBBgwFoAUN2vZwXDsYRGzWCN3loNB8EiQvdcwDwYDVR0TAQH/
+BAUwAwEB/zAeBgNVHREEFzAVghNraW9zay0xLmV4YW1wbGUub3JnMAUGAytlcANB
+APz3TiBS6VXOuX3RpN6dFizmD47x3m3r6hzIyp3/v7gRfOkxR7tnf17NAMFUvyi0
+psr0PHf51yFSPcmP8yP3JgA=
+-----END CERTIFICATE-----";
+
+    /// CN=kiosk-2, no SAN
+    const WITH_CN: &str = "-----BEGIN CERTIFICATE-----
+MIIBOjCB7aADAgECAhRuY0FwOCBXQF9n/TqRJ8Bvj2bA+zAFBgMrZXAwEjEQMA4G
+A1UEAwwHa2lvc2stMjAgFw0yNjEwMTgwNTUyMTFaGA8yMTI2MDkyNDA1NTIxMVow
+EjEQMA4GA1UEAwwHa2lvc2stMjAqMAUGAytlcAMhAI8E3FIrE5d7ilTirlezr0fB
+kckBxoQejmxa5E5aJl2+o1MwUTAdBgNVHQ4EFgQUN2vZwXDsYRGzWCN3loNB8EiQ
+vdcwHwYDVR0jBBgwFoAUN2vZwXDsYRGzWCN3loNB8EiQvdcwDwYDVR0TAQH/BAUw
+AwEB/zAFBgMrZXADQQC0JhQXCX55Q9Dk8aUP32XNExhhimQ6HrB+p63CECvzeFu/
+w2R+Qgi70C8t1r211GOfgO1OzxiwCePMhxHcXQoB
+-----END CERTIFICATE-----";
+
+    /// O=Encelade, no SAN nor CN
+    const ANONYMOUS: &str = "-----BEGIN CERTIFICATE-----
+MIIBPDCB76ADAgECAhRtptDux32lAXdo6G1KBfMsQxSpCDAFBgMrZXAwEzERMA8G
+A1UECgwIRW5jZWxhZGUwIBcNMjYxMDE4MDU1MjExWhgPMjEyNjA5MjQwNTUyMTFa
+MBMxETAPBgNVBAoMCEVuY2VsYWRlMCowBQYDK2VwAyEAjwTcUisTl3uKVOKuV7Ov
+R8GRyQHGhB6ObFrkTlomXb6jUzBRMB0GA1UdDgQWBBQ3a9nBcOxhEbNYI3eWg0Hw
+SJC91zAfBgNVHSMEGDAWgBQ3a9nBcOxhEbNYI3eWg0HwSJC91zAPBgNVHRMBAf8E
+BTADAQH/MAUGAytlcANBAGnYCBYomZfHLekhn5lzKJk8kai8FeX0xwDoKjWUwJSC
+ai/cKJL2NZw379lSk1khxVGVWKvofmmwDhfAKCa7XQQ=
+-----END CERTIFICATE-----";
+
+    /// Certificate as presented by a peer: DER encoded
+    fn peer_cert(pem: &str) -> Certificate {
+        let der = rustls_pemfile::certs(&mut pem.as_bytes())
+            .next()
+            .expect("pem certificate")
+            .expect("certificate parsed");
+
+        Certificate::from_pem(der.as_ref())
+    }
+
+    #[test]
+    fn alternative_name_is_preferred_to_common_name() {
+        assert_eq!(
+            identity(&peer_cert(WITH_SAN)).as_deref(),
+            Some("kiosk-1.example.org")
+        );
+    }
+
+    #[test]
+    fn common_name_identifies_a_certificate_without_alternative_name() {
+        assert_eq!(identity(&peer_cert(WITH_CN)).as_deref(), Some("kiosk-2"));
+    }
+
+    #[test]
+    fn certificate_without_name_has_no_identity() {
+        assert_eq!(identity(&peer_cert(ANONYMOUS)), None);
+    }
+
+    #[test]
+    fn invalid_certificate_has_no_identity() {
+        assert_eq!(identity(&Certificate::from_pem(b"not a certificate")), None);
+    }
+}
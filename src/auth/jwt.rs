@@ -0,0 +1,432 @@
+//! JWT bearer tokens
+//!
+//! Tokens are signed by an identity provider with RS256, ES256 or EdDSA, and verified with the
+//! public keys of its JWKS. The JWKS is read from a local file, or fetched from an url and
+//! refreshed periodically. A token without `kid` is verified with each key of its algorithm (eg:
+//! during a key rotation).
+
+use std::{
+    error::Error as StdError,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use jsonwebtoken::{
+    errors::ErrorKind,
+    jwk::{JwkSet, KeyAlgorithm, PublicKeyUse},
+    Algorithm, DecodingKey, Validation,
+};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use tokio::time::{self, MissedTickBehavior};
+use tonic::Status;
+
+use crate::{config::JwtConfig, permission::Role};
+
+/// Time given to the JWKS url to accept the connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Time given to the JWKS url to answer, connection included
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Algorithms accepted, symmetric ones are never accepted
+const ALGORITHMS: [Algorithm; 3] = [Algorithm::RS256, Algorithm::ES256, Algorithm::EdDSA];
+
+/// Claims of a verified token, added to the request extensions
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct Claims {
+    pub(crate) sub: String,
+    /// Any other claim (eg: iss, aud, exp, name, roles)
+    #[serde(flatten)]
+    pub(crate) other: Map<String, Value>,
+}
+
+impl Claims {
+    /// Roles listed by a claim, unknown roles are ignored
+    pub(crate) fn roles(&self, claim: &str) -> Vec<Role> {
+        let roles = match self.other.get(claim) {
+            Some(Value::Array(roles)) => roles.as_slice(),
+            Some(role @ Value::String(_)) => std::slice::from_ref(role),
+            _ => &[],
+        };
+
+        roles
+            .iter()
+            .filter_map(|role| Role::deserialize(role).ok())
+            .collect()
+    }
+
+    /// Name of the caller held by a claim
+    pub(crate) fn name(&self, claim: &str) -> Option<String> {
+        self.other
+            .get(claim)
+            .and_then(Value::as_str)
+            .map(str::to_owned)
+    }
+}
+
+/// A public key of the JWKS
+struct Key {
+    id: Option<String>,
+    /// Algorithm the key is restricted to, if any
+    algorithm: Option<Algorithm>,
+    key: DecodingKey,
+}
+
+pub(crate) struct Jwt {
+    config: JwtConfig,
+    keys: RwLock<Arc<Vec<Key>>>,
+    /// Client of the JWKS url
+    client: reqwest::Client,
+}
+
+impl Jwt {
+    /// Load the JWKS of the configuration
+    pub(crate) async fn load(config: JwtConfig) -> Result<Self, Box<dyn StdError>> {
+        let client = reqwest::Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .timeout(REQUEST_TIMEOUT)
+            .build()?;
+        let keys = Jwt::fetch(&client, &config.jwks).await?;
+
+        tracing::info!("use jwt: {} keys from {}", keys.len(), config.jwks);
+
+        Ok(Self {
+            config,
+            keys: RwLock::new(Arc::new(keys)),
+            client,
+        })
+    }
+
+    /// Claim listing the roles of the caller
+    pub(crate) fn roles_claim(&self) -> &str {
+        &self.config.roles_claim
+    }
+
+    /// Claim holding the name of the caller
+    pub(crate) fn name_claim(&self) -> &str {
+        &self.config.name_claim
+    }
+
+    /// Refresh the JWKS fetched from an url, forever
+    ///
+    /// A local JWKS file is read once.
+    pub(crate) async fn run(self: Arc<Self>) {
+        if !Jwt::is_url(&self.config.jwks) {
+            return;
+        }
+
+        let mut interval = time::interval(Duration::from_secs(self.config.refresh.max(1)));
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // the first tick is immediate, the keys are already loaded
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+
+            match Jwt::fetch(&self.client, &self.config.jwks).await {
+                Ok(keys) => {
+                    tracing::debug!("jwks refreshed: {} keys", keys.len());
+                    *self.keys.write().expect("jwks lock poisoned") = Arc::new(keys);
+                }
+                // the previous keys are kept
+                Err(e) => tracing::warn!("jwks refresh failed: {}", e),
+            }
+        }
+    }
+
+    /// Verify the signature and the claims of a token
+    #[allow(clippy::result_large_err)]
+    pub(crate) fn verify(&self, token: &str) -> Result<Claims, Status> {
+        let invalid = |reason: String| {
+            tracing::warn!("invalid bearer token: {}", reason);
+            Status::unauthenticated(format!("Invalid bearer token: {}", reason))
+        };
+
+        let header = jsonwebtoken::decode_header(token).map_err(|e| invalid(e.to_string()))?;
+        if !ALGORITHMS.contains(&header.alg) {
+            return Err(invalid(format!("algorithm {:?} not accepted", header.alg)));
+        }
+
+        let keys = self.keys.read().expect("jwks lock poisoned").clone();
+        let mut candidates = keys
+            .iter()
+            .filter(|key| header.kid.is_none() || key.id == header.kid)
+            .filter(|key| {
+                key.algorithm
+                    .is_none_or(|algorithm| algorithm == header.alg)
+            })
+            .peekable();
+
+        if candidates.peek().is_none() {
+            return Err(invalid(format!(
+                "no {:?} key {} in the jwks",
+                header.alg,
+                header.kid.as_deref().unwrap_or_default()
+            )));
+        }
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&self.config.issuer]);
+        validation.set_audience(&[&self.config.audience]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+        validation.leeway = self.config.leeway;
+        validation.validate_nbf = true;
+
+        let mut failure = None;
+
+        for key in candidates {
+            match jsonwebtoken::decode::<Claims>(token, &key.key, &validation) {
+                Ok(data) => return Ok(data.claims),
+                // signed by this key, the claims are rejected
+                Err(e) if Jwt::is_claims_error(e.kind()) => return Err(invalid(e.to_string())),
+                // maybe signed by another key
+                Err(e) => {
+                    failure.get_or_insert(e);
+                }
+            }
+        }
+
+        Err(invalid(failure.map(|e| e.to_string()).unwrap_or_default()))
+    }
+
+    /// Error of a valid signature, the claims being checked after it
+    fn is_claims_error(kind: &ErrorKind) -> bool {
+        matches!(
+            kind,
+            ErrorKind::MissingRequiredClaim(_)
+                | ErrorKind::ExpiredSignature
+                | ErrorKind::InvalidIssuer
+                | ErrorKind::InvalidAudience
+                | ErrorKind::InvalidSubject
+                | ErrorKind::ImmatureSignature
+        )
+    }
+
+    fn is_url(jwks: &str) -> bool {
+        jwks.starts_with("https://") || jwks.starts_with("http://")
+    }
+
+    /// Signature keys of a JWKS file or url
+    async fn fetch(client: &reqwest::Client, jwks: &str) -> Result<Vec<Key>, Box<dyn StdError>> {
+        let set: JwkSet = if Jwt::is_url(jwks) {
+            client
+                .get(jwks)
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?
+        } else {
+            serde_json::from_slice(&tokio::fs::read(jwks).await?)?
+        };
+
+        let mut keys = Vec::with_capacity(set.keys.len());
+
+        for jwk in set.keys {
+            if matches!(jwk.common.public_key_use, Some(PublicKeyUse::Encryption)) {
+                continue;
+            }
+
+            let algorithm = match jwk.common.key_algorithm {
+                None => None,
+                Some(KeyAlgorithm::RS256) => Some(Algorithm::RS256),
+                Some(KeyAlgorithm::ES256) => Some(Algorithm::ES256),
+                Some(KeyAlgorithm::EdDSA) => Some(Algorithm::EdDSA),
+                // keys of other algorithms can't verify an accepted token
+                Some(_) => continue,
+            };
+
+            keys.push(Key {
+                id: jwk.common.key_id.clone(),
+                algorithm,
+                key: DecodingKey::from_jwk(&jwk)?,
+            });
+        }
+
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use chrono::Utc;
+    use ed25519_dalek::SigningKey;
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::json;
+    use tonic::Code;
+
+    use super::*;
+
+    const ISSUER: &str = "https://idp.example.org";
+    const AUDIENCE: &str = "register";
+
+    /// PKCS#8 prefix of an Ed25519 private key, followed by its 32 bytes seed
+    const PKCS8_ED25519: [u8; 16] = [
+        0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04,
+        0x20,
+    ];
+
+    /// Ed25519 key of the identity provider
+    struct Signer {
+        kid: &'static str,
+        seed: [u8; 32],
+    }
+
+    impl Signer {
+        fn jwk(&self) -> Value {
+            let public = SigningKey::from_bytes(&self.seed).verifying_key();
+
+            json!({
+                "kty": "OKP",
+                "crv": "Ed25519",
+                "use": "sig",
+                "alg": "EdDSA",
+                "kid": self.kid,
+                "x": URL_SAFE_NO_PAD.encode(public.as_bytes()),
+            })
+        }
+
+        fn sign(&self, claims: &Value, with_kid: bool) -> String {
+            let mut header = Header::new(Algorithm::EdDSA);
+            header.kid = with_kid.then(|| self.kid.to_owned());
+
+            let der = [PKCS8_ED25519.as_slice(), &self.seed].concat();
+
+            jsonwebtoken::encode(&header, claims, &EncodingKey::from_ed_der(&der))
+                .expect("token signed")
+        }
+    }
+
+    const CURRENT: Signer = Signer {
+        kid: "current",
+        seed: [1; 32],
+    };
+    const NEXT: Signer = Signer {
+        kid: "next",
+        seed: [2; 32],
+    };
+    const UNLISTED: Signer = Signer {
+        kid: "unlisted",
+        seed: [3; 32],
+    };
+
+    /// Verifier of a local JWKS file listing the current and the next keys
+    async fn jwt() -> Jwt {
+        let jwks = json!({ "keys": [CURRENT.jwk(), NEXT.jwk()] });
+        let path = std::env::temp_dir().join(format!(
+            "jwks-{}-{}.json",
+            std::process::id(),
+            mongodb::bson::oid::ObjectId::new()
+        ));
+        tokio::fs::write(&path, jwks.to_string())
+            .await
+            .expect("jwks written");
+
+        let config = JwtConfig {
+            jwks: path.to_string_lossy().into_owned(),
+            issuer: ISSUER.to_owned(),
+            audience: AUDIENCE.to_owned(),
+            leeway: 0,
+            roles_claim: "roles".to_owned(),
+            name_claim: "name".to_owned(),
+            refresh: 3600,
+        };
+
+        let jwt = Jwt::load(config).await.expect("jwks loaded");
+        let _ = tokio::fs::remove_file(&path).await;
+
+        jwt
+    }
+
+    fn claims() -> Value {
+        json!({
+            "sub": "alice",
+            "iss": ISSUER,
+            "aud": AUDIENCE,
+            "exp": Utc::now().timestamp() + 600,
+            "roles": ["clerk"],
+        })
+    }
+
+    fn rejected(jwt: &Jwt, token: &str, reason: &str) {
+        let status = jwt.verify(token).expect_err("token accepted");
+        assert_eq!(status.code(), Code::Unauthenticated);
+        assert!(status.message().contains(reason), "{}", status.message());
+    }
+
+    #[tokio::test]
+    async fn valid_token_is_accepted() {
+        let jwt = jwt().await;
+
+        let claims = jwt
+            .verify(&CURRENT.sign(&claims(), true))
+            .expect("token rejected");
+
+        assert_eq!(claims.sub, "alice");
+        assert_eq!(claims.roles(jwt.roles_claim()), [Role::Clerk]);
+    }
+
+    #[tokio::test]
+    async fn token_without_kid_is_verified_with_each_key() {
+        let jwt = jwt().await;
+
+        for signer in [CURRENT, NEXT] {
+            let claims = jwt
+                .verify(&signer.sign(&claims(), false))
+                .expect("token rejected");
+            assert_eq!(claims.sub, "alice");
+        }
+
+        rejected(&jwt, &UNLISTED.sign(&claims(), false), "InvalidSignature");
+    }
+
+    #[tokio::test]
+    async fn wrong_issuer_or_audience_is_rejected() {
+        let jwt = jwt().await;
+
+        let mut claims = claims();
+        claims["iss"] = json!("https://other.example.org");
+        rejected(&jwt, &CURRENT.sign(&claims, true), "InvalidIssuer");
+
+        let mut claims = self::claims();
+        claims["aud"] = json!("other");
+        rejected(&jwt, &CURRENT.sign(&claims, true), "InvalidAudience");
+        rejected(&jwt, &NEXT.sign(&claims, false), "InvalidAudience");
+    }
+
+    #[tokio::test]
+    async fn expired_token_is_rejected() {
+        let jwt = jwt().await;
+
+        let mut claims = claims();
+        claims["exp"] = json!(Utc::now().timestamp() - 60);
+
+        rejected(&jwt, &CURRENT.sign(&claims, true), "ExpiredSignature");
+    }
+
+    #[tokio::test]
+    async fn unknown_kid_is_rejected() {
+        let jwt = jwt().await;
+
+        rejected(
+            &jwt,
+            &UNLISTED.sign(&claims(), true),
+            "no EdDSA key unlisted",
+        );
+    }
+
+    #[tokio::test]
+    async fn symmetric_algorithm_is_rejected() {
+        let jwt = jwt().await;
+
+        let token = jsonwebtoken::encode(
+            &Header::new(Algorithm::HS256),
+            &claims(),
+            &EncodingKey::from_secret(b"secret"),
+        )
+        .expect("token signed");
+
+        rejected(&jwt, &token, "algorithm HS256 not accepted");
+    }
+}
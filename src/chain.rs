@@ -0,0 +1,453 @@
+//! Tamper-evident hash chain over the record history
+//!
+//! Each history entry holds `hmac-sha256(key, previous hash | canonical BSON of the entry)`, the
+//! first entry chains from an empty hash. The key is a server secret: writing the collection is
+//! not enough to recompute the chain. The entries hold the fields they set, so replaying them
+//! rebuilds the record: an edit of the record or of its history breaks the chain. The hash of the
+//! `Complete` entry seals the record.
+//!
+//! The entries written before the chain are not hashed, the first chained entry links their
+//! hash instead of an empty one.
+
+use std::{error::Error as StdError, sync::Arc};
+
+use hmac::{Hmac, Mac};
+use mongodb::bson::{from_document, to_document, to_vec, Bson, Document};
+use serde::Deserialize;
+use sha2::Sha256;
+
+use crate::{
+    config::ChainConfig,
+    error::Error,
+    mongodb::{Attachment, Record, Traces, Transition},
+    workflow,
+};
+
+const MIN_KEY_LENGTH: usize = 32;
+
+/// Result of the verification of a record
+#[derive(Debug, PartialEq)]
+pub(crate) enum Verdict {
+    Intact,
+    /// The first altered entry, none if the entries are intact but not the record
+    Altered {
+        entry: Option<usize>,
+        reason: &'static str,
+    },
+    /// The chain starts after the creation of the record: the history and the fields it sets are
+    /// intact since then, the fields set before the history are not verified
+    PartiallyChained,
+    /// The record has been created before the hash chain
+    Unchained,
+}
+
+/// Fields rebuilt by replaying the history
+#[derive(Debug, Default, PartialEq, Deserialize)]
+struct Written {
+    #[serde(default)]
+    summary: String,
+    created: Option<i64>,
+    traces: Option<Traces>,
+    #[serde(default)]
+    attachments: Vec<Attachment>,
+}
+
+/// Hash of the last entry, empty before the first one
+pub(crate) fn last_hash(history: &[Transition]) -> &str {
+    history
+        .last()
+        .and_then(|entry| entry.hash.as_deref())
+        .unwrap_or_default()
+}
+
+/// Keyed hash chain
+#[derive(Clone)]
+pub(crate) struct Chain {
+    key: Arc<[u8]>,
+}
+
+impl Chain {
+    pub(crate) fn new(key: &[u8]) -> Self {
+        Self { key: key.into() }
+    }
+
+    pub(crate) fn from_config(config: &ChainConfig) -> Result<Self, Box<dyn StdError>> {
+        match &config.key {
+            Some(key) if key.len() < MIN_KEY_LENGTH => {
+                Err(format!("chain key is at least {} bytes", MIN_KEY_LENGTH).into())
+            }
+            Some(key) => Ok(Chain::new(key.as_bytes())),
+            None => {
+                tracing::warn!("chain key not configured, the hash chain can be forged");
+                Ok(Chain::new(b""))
+            }
+        }
+    }
+
+    /// The entry linked to the end of the history
+    pub(crate) fn linked(
+        &self,
+        history: &[Transition],
+        entry: Transition,
+    ) -> Result<Transition, Error> {
+        let hash = self.link(&self.head(history)?, &entry)?;
+
+        Ok(Transition {
+            hash: Some(hash),
+            ..entry
+        })
+    }
+
+    /// Hash the next entry links: the hash of the last entry, or of the entries written before
+    /// the chain
+    fn head(&self, history: &[Transition]) -> Result<String, Error> {
+        match history.last() {
+            Some(Transition {
+                hash: Some(hash), ..
+            }) => Ok(hash.clone()),
+            _ => history
+                .iter()
+                .try_fold(String::new(), |previous, entry| self.link(&previous, entry)),
+        }
+    }
+
+    fn link(&self, previous: &str, entry: &Transition) -> Result<String, Error> {
+        let canonical = Transition {
+            hash: None,
+            ..entry.clone()
+        };
+        let bytes = to_vec(&canonical).map_err(|e| Error::Internal(e.to_string()))?;
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key)
+            .map_err(|e| Error::Internal(e.to_string()))?;
+        mac.update(previous.as_bytes());
+        mac.update(&bytes);
+
+        Ok(hex::encode(mac.finalize().into_bytes()))
+    }
+
+    /// Check the hash chain and replay the history to rebuild the record
+    pub(crate) fn verify(&self, record: &Record) -> Verdict {
+        let Some(start) = record.history.iter().position(|entry| entry.hash.is_some()) else {
+            return Verdict::Unchained;
+        };
+        let (unchained, chained) = record.history.split_at(start);
+
+        let altered = |entry, reason| Verdict::Altered { entry, reason };
+
+        let Ok(mut previous) = self.head(unchained) else {
+            return altered(Some(0), "malformed entry");
+        };
+        // without entries before the chain, the state before the first chained one is unknown
+        let mut state = match unchained.last() {
+            Some(entry) => Some(entry.to),
+            None => chained[0].from,
+        };
+        let mut written = Document::new();
+        for entry in unchained {
+            apply(&mut written, &entry.set);
+        }
+
+        for (index, entry) in chained.iter().enumerate() {
+            let index = start + index;
+
+            let Some(hash) = &entry.hash else {
+                return altered(Some(index), "missing hash");
+            };
+            if self.link(&previous, entry).ok().as_ref() != Some(hash) {
+                return altered(Some(index), "hash mismatch");
+            }
+            if entry.from != state {
+                return altered(Some(index), "state not reached by the previous entry");
+            }
+
+            apply(&mut written, &entry.set);
+            state = Some(entry.to);
+            previous.clone_from(hash);
+        }
+
+        let from_creation = record.history[0].from.is_none();
+        let consistent = if from_creation {
+            Chain::matches(record, written)
+        } else {
+            Chain::matches_written(record, &written)
+        };
+
+        let sealed = match record.state {
+            workflow::SEALED => record.seal.as_ref() == Some(&previous),
+            _ => record.seal.is_none(),
+        };
+
+        if state != Some(record.state) {
+            altered(None, "state differs from the history")
+        } else if !consistent {
+            altered(None, "fields differ from the history")
+        } else if !sealed {
+            altered(None, "seal differs from the last hash")
+        } else if start > 0 || !from_creation {
+            Verdict::PartiallyChained
+        } else {
+            Verdict::Intact
+        }
+    }
+
+    /// The fields of the record are the ones rebuilt from its whole history
+    fn matches(record: &Record, written: Document) -> bool {
+        let Ok(written) = from_document::<Written>(written) else {
+            return false;
+        };
+
+        written
+            == Written {
+                summary: record.summary.clone(),
+                created: record.created,
+                traces: record.traces.clone(),
+                attachments: record.attachments.clone(),
+            }
+    }
+
+    /// The fields set by the history hold their last value, the other fields of a record created
+    /// before its history are unknown
+    fn matches_written(record: &Record, written: &Document) -> bool {
+        let Ok(current) = to_document(record) else {
+            return false;
+        };
+
+        record
+            .history
+            .iter()
+            .flat_map(|entry| entry.set.keys())
+            .all(|path| field(&current, path) == field(written, path))
+    }
+}
+
+/// Value of a field path (eg: `traces.collected.outside`)
+fn field<'a>(document: &'a Document, path: &str) -> Option<&'a Bson> {
+    let (parent, key) = match path.rsplit_once('.') {
+        Some((parent, key)) => (field(document, parent)?.as_document()?, key),
+        None => (document, path),
+    };
+
+    parent.get(key)
+}
+
+/// Apply a `$set` document, keys are field paths (eg: `traces.collected.outside`)
+pub(crate) fn apply(document: &mut Document, set: &Document) {
+    for (path, value) in set {
+        let mut parent = &mut *document;
+        let mut keys = path.split('.').peekable();
+
+        while let Some(key) = keys.next() {
+            if keys.peek().is_none() {
+                parent.insert(key, value.clone());
+                break;
+            }
+
+            if !matches!(parent.get(key), Some(Bson::Document(_))) {
+                parent.insert(key, Document::new());
+            }
+            let Some(Bson::Document(child)) = parent.get_mut(key) else {
+                unreachable!("a document has just been inserted");
+            };
+            parent = child;
+        }
+    }
+}
+
+/// Apply a `$set` document on a record
+pub(crate) fn apply_to_record(record: &mut Record, set: &Document) -> Result<(), Error> {
+    let mut document = to_document(record).map_err(|e| Error::Internal(e.to_string()))?;
+    apply(&mut document, set);
+
+    let score = record.score;
+    *record = from_document(document).map_err(|e| Error::Internal(e.to_string()))?;
+    record.score = score;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::doc;
+
+    use super::*;
+    use crate::mongodb::RecordState;
+
+    const KEY: &[u8] = b"a test key of at least 32 bytes!";
+
+    fn entry(from: Option<RecordState>, to: RecordState, set: Document) -> Transition {
+        Transition {
+            from,
+            to,
+            time: 0,
+            caller: "test".to_owned(),
+            rpc: "Test".to_owned(),
+            set,
+            hash: None,
+        }
+    }
+
+    /// Apply an entry as the storages do, linked to the chain if any
+    fn push(chain: Option<&Chain>, record: &mut Record, entry: Transition) {
+        let entry = match chain {
+            Some(chain) => chain.linked(&record.history, entry).expect("entry linked"),
+            None => entry,
+        };
+
+        apply_to_record(record, &entry.set).expect("entry applied");
+        record.state = entry.to;
+        record.history.push(entry);
+    }
+
+    fn empty() -> Record {
+        Record {
+            id: None,
+            api_version: 1,
+            created: None,
+            summary: String::new(),
+            traces: None,
+            state: RecordState::Draft,
+            transitioned: None,
+            history: vec![],
+            seal: None,
+            attachments: vec![],
+            score: None,
+        }
+    }
+
+    /// A draft created, updated then submitted
+    fn submitted(chain: Option<&Chain>) -> Record {
+        let mut record = empty();
+
+        let entries = [
+            entry(
+                None,
+                RecordState::Draft,
+                doc! { "summary": "first", "created": 100_i64 },
+            ),
+            entry(
+                Some(RecordState::Draft),
+                RecordState::Draft,
+                doc! { "summary": "second" },
+            ),
+            entry(Some(RecordState::Draft), RecordState::Created, doc! {}),
+        ];
+        for entry in entries {
+            push(chain, &mut record, entry);
+        }
+
+        record
+    }
+
+    fn altered(entry: Option<usize>, reason: &'static str) -> Verdict {
+        Verdict::Altered { entry, reason }
+    }
+
+    #[test]
+    fn chained_record_is_intact() {
+        let chain = Chain::new(KEY);
+
+        assert_eq!(chain.verify(&submitted(Some(&chain))), Verdict::Intact);
+    }
+
+    #[test]
+    fn edited_entry_is_altered() {
+        let chain = Chain::new(KEY);
+        let mut record = submitted(Some(&chain));
+
+        record.history[1].set = doc! { "summary": "forged" };
+        record.summary = "forged".to_owned();
+
+        assert_eq!(chain.verify(&record), altered(Some(1), "hash mismatch"));
+    }
+
+    #[test]
+    fn removed_entry_is_altered() {
+        let chain = Chain::new(KEY);
+        let mut record = submitted(Some(&chain));
+
+        record.history.remove(1);
+
+        assert_eq!(chain.verify(&record), altered(Some(1), "hash mismatch"));
+    }
+
+    #[test]
+    fn edited_field_is_altered() {
+        let chain = Chain::new(KEY);
+        let mut record = submitted(Some(&chain));
+
+        record.summary = "forged".to_owned();
+
+        assert_eq!(
+            chain.verify(&record),
+            altered(None, "fields differ from the history")
+        );
+    }
+
+    #[test]
+    fn chain_of_another_key_is_altered() {
+        let forged = Chain::new(b"a key unknown to the service ...");
+        let record = submitted(Some(&forged));
+
+        assert_eq!(
+            Chain::new(KEY).verify(&record),
+            altered(Some(0), "hash mismatch")
+        );
+    }
+
+    #[test]
+    fn unhashed_history_is_unchained() {
+        let chain = Chain::new(KEY);
+
+        assert_eq!(chain.verify(&submitted(None)), Verdict::Unchained);
+    }
+
+    #[test]
+    fn history_chained_after_unhashed_entries_is_partially_chained() {
+        let chain = Chain::new(KEY);
+        let mut record = submitted(None);
+        push(
+            Some(&chain),
+            &mut record,
+            entry(
+                Some(RecordState::Created),
+                RecordState::CollectClientInside,
+                doc! {},
+            ),
+        );
+
+        assert_eq!(chain.verify(&record), Verdict::PartiallyChained);
+
+        // the unhashed entries are linked by the first chained one
+        record.history[1].set = doc! { "summary": "forged" };
+        record.summary = "forged".to_owned();
+
+        assert_eq!(chain.verify(&record), altered(Some(3), "hash mismatch"));
+    }
+
+    #[test]
+    fn history_chained_after_creation_is_partially_chained() {
+        let chain = Chain::new(KEY);
+        let mut record = Record {
+            summary: "created before the history".to_owned(),
+            created: Some(100),
+            state: RecordState::Created,
+            ..empty()
+        };
+        push(
+            Some(&chain),
+            &mut record,
+            entry(
+                Some(RecordState::Created),
+                RecordState::CollectClientInside,
+                doc! { "traces.collected.inside": 200_i64 },
+            ),
+        );
+
+        assert_eq!(chain.verify(&record), Verdict::PartiallyChained);
+
+        // the fields set by the history are still verified
+        record.history[0].set = doc! { "traces.collected.inside": 300_i64 };
+        assert_eq!(chain.verify(&record), altered(Some(0), "hash mismatch"));
+    }
+}
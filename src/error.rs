@@ -0,0 +1,134 @@
+//! Register errors
+//!
+//! Domain errors returned by the storage and mapped to grpc status by the service.
+
+use core::fmt;
+
+use mongodb::error::{ErrorKind, RETRYABLE_WRITE_ERROR, TRANSIENT_TRANSACTION_ERROR};
+
+use crate::{
+    mongodb::RecordState,
+    workflow::{Guard, Violation},
+};
+
+#[derive(Debug)]
+pub(crate) enum Error {
+    /// The request is malformed
+    InvalidArgument {
+        field: &'static str,
+        description: String,
+    },
+    /// No record with this id
+    NotFound { id: String },
+    /// No signature image with this id
+    ImageNotFound { id: String },
+    /// No attachment with this id on the record
+    AttachmentNotFound { id: String },
+    /// The record is not in a state allowing the request
+    FailedPrecondition {
+        id: String,
+        current: RecordState,
+        expected: Vec<RecordState>,
+    },
+    /// No public key is registered for this signer
+    SignerKeyNotFound { signer: String },
+    /// The signature is not valid for the registered key of the signer
+    SignatureMismatch { signer: String },
+    /// The declared signer is not the authenticated caller
+    SignerNotCaller { signer: String, caller: String },
+    /// A watch can't be resumed from this point, the changes are not kept anymore
+    ResumePointExpired(String),
+    /// The storage can't be reached, the request can be retried later
+    Unavailable(String),
+    /// Any other storage error
+    Internal(String),
+}
+
+impl Error {
+    pub(crate) fn invalid_argument(field: &'static str, description: impl Into<String>) -> Self {
+        Error::InvalidArgument {
+            field,
+            description: description.into(),
+        }
+    }
+
+    pub(crate) fn not_found(id: impl fmt::Display) -> Self {
+        Error::NotFound { id: id.to_string() }
+    }
+
+    /// Error for a workflow violation on a record
+    pub(crate) fn violation(id: impl fmt::Display, violation: Violation) -> Self {
+        match violation {
+            Violation::State { current, expected } => Error::FailedPrecondition {
+                id: id.to_string(),
+                current,
+                expected: expected.to_vec(),
+            },
+            Violation::Guard(guard) => {
+                let (field, description) = match guard {
+                    Guard::TimeSet => ("time", "time is required"),
+                    Guard::SignerNamed => ("signer.name", "signer name is required"),
+                    Guard::SignatureSet => ("signer.signature", "signature is required"),
+                };
+
+                Error::invalid_argument(field, description)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidArgument { field, description } => {
+                write!(f, "invalid {}: {}", field, description)
+            }
+            Error::NotFound { id } => write!(f, "record {} not found", id),
+            Error::ImageNotFound { id } => write!(f, "signature image {} not found", id),
+            Error::AttachmentNotFound { id } => write!(f, "attachment {} not found", id),
+            Error::FailedPrecondition {
+                id,
+                current,
+                expected,
+            } => write!(
+                f,
+                "record {} is {:?}, expected one of {:?}",
+                id, current, expected
+            ),
+            Error::SignerKeyNotFound { signer } => {
+                write!(f, "no public key registered for signer {}", signer)
+            }
+            Error::SignatureMismatch { signer } => {
+                write!(f, "signature of {} doesn't match its public key", signer)
+            }
+            Error::SignerNotCaller { signer, caller } => {
+                write!(f, "{} can't sign as {}", caller, signer)
+            }
+            Error::ResumePointExpired(point) => write!(
+                f,
+                "watch can't be resumed from {}, changes are not kept anymore",
+                point
+            ),
+            Error::Unavailable(e) => write!(f, "storage unavailable: {}", e),
+            Error::Internal(e) => write!(f, "storage error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<mongodb::error::Error> for Error {
+    fn from(value: mongodb::error::Error) -> Self {
+        let retryable = value.contains_label(RETRYABLE_WRITE_ERROR)
+            || value.contains_label(TRANSIENT_TRANSACTION_ERROR);
+
+        match *value.kind {
+            ErrorKind::ServerSelection { .. }
+            | ErrorKind::ConnectionPoolCleared { .. }
+            | ErrorKind::DnsResolve { .. }
+            | ErrorKind::Io(_) => Error::Unavailable(value.to_string()),
+            _ if retryable => Error::Unavailable(value.to_string()),
+            _ => Error::Internal(value.to_string()),
+        }
+    }
+}
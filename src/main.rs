@@ -3,20 +3,37 @@
 
 //! Register Service exposed by grpc and grpc-web
 
-use std::process::ExitCode;
+use std::{env, process::ExitCode};
 
 #[macro_use]
 extern crate num_derive;
 
+mod attachment;
+mod audit;
 mod auth;
+mod chain;
 mod config;
+mod error;
 mod mongodb;
 mod observability;
+mod overdue;
+mod permission;
 mod register;
 mod service;
+mod signature;
+mod storage;
+mod tls;
+mod workflow;
 
 #[tokio::main]
 async fn main() -> ExitCode {
+    // render the register workflow and exit
+    if env::args().nth(1).as_deref() == Some("--workflow-dot") {
+        print!("{}", workflow::to_dot());
+
+        return ExitCode::SUCCESS;
+    }
+
     let terminated = service::run().await;
 
     match terminated {
@@ -1,275 +1,1076 @@
 //! MongoDB abstraction for Register collection
 
+mod audit_types;
+mod change_feed;
+mod key_types;
 mod register_types;
 mod string_id;
 mod traces_for;
 
+use crate::chain::Chain;
 use crate::config::MongoDbConfig;
+use crate::error::Error;
+use crate::storage::{
+    AuditQuery, AuditStream, ByteStream, Change, ChangeFeed, ChangeKind, File, Origin, PageKey,
+    Period, RecordStream, SearchQuery, Snapshot, Sort, SortField, SortValue, Stats, Storage,
+    WatchFilter, WatchStart, CHUNK_SIZE,
+};
+use crate::workflow::{self, Action, Field, Payload, TraceField};
 
-pub(crate) use self::register_types::{Record, RecordState, Signer, Trace};
+pub(crate) use self::audit_types::AuditEntry;
+use self::change_feed::MongoFeed;
+pub(crate) use self::key_types::SignerKey;
+pub(crate) use self::register_types::{
+    Attachment, Phase, Record, RecordState, Signer, Trace, Traces, Transition,
+};
 pub(crate) use self::string_id::StringId;
 pub(crate) use self::traces_for::{SignatureTraceFor, TimeTraceFor};
 
 use std::time::Duration;
 
 use chrono::Utc;
-use mongodb::change_stream::event::ChangeStreamEvent;
-use mongodb::change_stream::ChangeStream;
-use mongodb::options::{ChangeStreamOptions, FullDocumentType};
-use mongodb::Cursor;
+use futures_util::{stream, AsyncReadExt, AsyncWriteExt};
+use mongodb::bson::oid::ObjectId;
+use mongodb::change_stream::event::{ChangeStreamEvent, OperationType, ResumeToken};
+use mongodb::error::ErrorKind;
+use mongodb::gridfs::{GridFsBucket, GridFsDownloadStream, GridFsUploadStream};
+use mongodb::options::{
+    ChangeStreamOptions, FindOptions, FullDocumentType, GridFsBucketOptions, GridFsUploadOptions,
+    IndexOptions, ReplaceOptions, SessionOptions,
+};
 use mongodb::{
-    bson::{doc, to_bson},
-    error::Error,
+    bson::{doc, from_bson, from_document, to_bson, Bson, Document, Timestamp},
     options::ClientOptions,
-    results::{DeleteResult, InsertOneResult, UpdateResult},
-    Client, Collection,
+    Client, Collection, IndexModel,
 };
+use num_traits::FromPrimitive;
+use sha2::{Digest, Sha256};
+use tokio_stream::StreamExt;
 
 /// A MongoDB Collection of [Record] type
 pub(crate) struct Mongo {
     pub(crate) register: Collection<Record>,
+    /// Append-only, entries are never updated nor deleted by the service
+    pub(crate) audit: Collection<AuditEntry>,
+    pub(crate) keys: Collection<SignerKey>,
+    pub(crate) signatures: GridFsBucket,
+    pub(crate) attachments: GridFsBucket,
+    chain: Chain,
 }
 
 static API_VERSION_1: i32 = 1;
 
+/// Reads and conditional updates of a record before giving up on concurrent updates
+const MAX_TRANSITION_ATTEMPTS: usize = 3;
+
+/// Server error codes when a change stream can't be resumed
+const CHANGE_STREAM_FATAL_ERROR: i32 = 280;
+const CHANGE_STREAM_HISTORY_LOST: i32 = 286;
+
 impl Mongo {
-    pub(crate) async fn new(config: MongoDbConfig) -> Result<Self, Error> {
+    pub(crate) async fn new(config: &MongoDbConfig, chain: Chain) -> Result<Self, Error> {
         let client_options = ClientOptions::parse(&config.uri).await?;
 
         let client = Client::with_options(client_options)?;
 
-        let register = client.database(&config.db).collection(&config.collection);
+        let database = client.database(&config.db);
+        let register = database.collection(&config.collection);
+        let audit = database.collection(&config.audit_collection);
+        let keys = database.collection(&config.keys_collection);
+        let signatures = database.gridfs_bucket(
+            GridFsBucketOptions::builder()
+                .bucket_name(config.signatures_bucket.clone())
+                .build(),
+        );
 
-        Ok(Mongo { register })
-    }
+        let attachments = database.gridfs_bucket(
+            GridFsBucketOptions::builder()
+                .bucket_name(config.attachments_bucket.clone())
+                .build(),
+        );
 
-    pub(crate) async fn insert_draft(&self, summary: String) -> Result<InsertOneResult, Error> {
-        let draft = Record {
-            id: None,
-            api_version: API_VERSION_1,
-            created: Some(Utc::now().timestamp()),
-            summary: summary,
-            traces: None,
-            state: RecordState::Draft,
+        let mongo = Mongo {
+            register,
+            audit,
+            keys,
+            signatures,
+            attachments,
+            chain,
         };
+        mongo.ensure_indexes().await?;
+
+        Ok(mongo)
+    }
+
+    /// Create the indexes required by the searches, if missing
+    async fn ensure_indexes(&self) -> Result<(), Error> {
+        // only one text index by collection
+        let text = IndexModel::builder()
+            .keys(doc! {
+                "summary": "text",
+                "traces.collected.client.name": "text",
+                "traces.collected.pqrs.name": "text",
+                "traces.returned.client.name": "text",
+                "traces.returned.pqrs.name": "text",
+            })
+            .options(IndexOptions::builder().name("text".to_owned()).build())
+            .build();
+
+        // search by state and creation time, also used to sort and paginate
+        let mut indexes = vec![text, Mongo::index(&["state", "created", "_id"])];
+
+        // search by trace time or signer name, usually in a creation time range
+        for field in [
+            TraceField::CollectedInside,
+            TraceField::CollectedOutside,
+            TraceField::ReturnedInside,
+            TraceField::ReturnedOutside,
+        ] {
+            indexes.push(Mongo::index(&[field.path(), "created"]));
+        }
+        for field in [
+            TraceField::CollectedClient,
+            TraceField::CollectedPqrs,
+            TraceField::ReturnedClient,
+            TraceField::ReturnedPqrs,
+        ] {
+            indexes.push(Mongo::index(&[
+                &format!("{}.name", field.path()),
+                "created",
+            ]));
+        }
 
-        self.register.insert_one(draft, None).await
+        self.register.create_indexes(indexes, None).await?;
+
+        // audit queries by time, optionally for a record
+        self.audit
+            .create_indexes(
+                [
+                    Mongo::index(&["time", "_id"]),
+                    Mongo::index(&["record_id", "time", "_id"]),
+                ],
+                None,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Ascending compound index
+    fn index(fields: &[&str]) -> IndexModel {
+        let mut keys = Document::new();
+        for field in fields {
+            keys.insert(*field, 1);
+        }
+
+        IndexModel::builder().keys(keys).build()
+    }
+
+    /// Error explaining why a record has not been matched by an update
+    async fn unmatched(&self, id: ObjectId, expected: &[RecordState]) -> Error {
+        match self.register.find_one(doc! { "_id": id }, None).await {
+            Ok(None) => Error::not_found(id),
+            Ok(Some(record)) => Error::FailedPrecondition {
+                id: id.to_string(),
+                current: record.state,
+                expected: expected.to_vec(),
+            },
+            Err(e) => e.into(),
+        }
     }
 
-    pub(crate) async fn update_draft(
+    /// Apply a workflow step on a record
+    ///
+    /// The record is updated only if its state is allowed by the step.
+    /// The update and its history entry, linked to the hash chain, are written by the same
+    /// atomic update, only if the history has not grown since the record was read.
+    async fn transition(
         &self,
         id: StringId,
-        summary: String,
-    ) -> Result<UpdateResult, Error> {
-        let query = doc! {
-            "_id": id.to_object_id()?,
-            "state": RecordState::Draft,
+        action: Action,
+        payload: Payload,
+        origin: Origin,
+    ) -> Result<(), Error> {
+        let step = workflow::step(action);
+
+        step.check_guards(&payload)
+            .map_err(|violation| Error::violation(&id, violation))?;
+
+        let id = id.to_object_id()?;
+
+        for _ in 0..MAX_TRANSITION_ATTEMPTS {
+            let record = self
+                .register
+                .find_one(doc! { "_id": id }, None)
+                .await?
+                .ok_or_else(|| Error::not_found(id))?;
+
+            step.check_state(record.state)
+                .map_err(|violation| Error::violation(id, violation))?;
+
+            let write = match step.writes {
+                Some(field) => Mongo::write(field, &payload, &record)?,
+                None => Document::new(),
+            };
+            let entry = self.chain.linked(
+                &record.history,
+                origin.transition(Some(record.state), step.to, write.clone()),
+            )?;
+
+            let mut set = doc! {
+                "state": step.to,
+            };
+            if step.changes_state() {
+                set.insert("transitioned", Utc::now().timestamp());
+            }
+            if step.to == workflow::SEALED {
+                set.insert("seal", entry.hash.clone());
+            }
+            set.extend(write.clone());
+
+            let query = match record.history.len() {
+                0 => doc! {
+                    "_id": id,
+                    "state": record.state,
+                    "history.0": { "$exists": false },
+                },
+                length => doc! {
+                    "_id": id,
+                    "state": record.state,
+                    "history": { "$size": length as i64 },
+                },
+            };
+            let entry = to_bson(&entry).map_err(|e| Error::Internal(e.to_string()))?;
+            let update = doc! {
+                "$set": set,
+                "$push": { "history": entry },
+            };
+
+            let result = self.register.update_one(query, update, None).await?;
+
+            if result.matched_count > 0 {
+                return Ok(());
+            }
+        }
+
+        Err(Error::Unavailable(format!(
+            "record {} is updated concurrently",
+            id
+        )))
+    }
+
+    /// Fields to set for a written field (`$set` document), also kept in the record history
+    ///
+    /// The attachments are written as a whole, from the ones of the record.
+    pub(crate) fn write(
+        field: Field,
+        payload: &Payload,
+        record: &Record,
+    ) -> Result<Document, Error> {
+        let value = match (field, payload) {
+            (Field::Summary, Payload::Summary(summary)) => Bson::String(summary.clone()),
+            (Field::Created, _) => Bson::Int64(Utc::now().timestamp()),
+            (Field::Trace(_), Payload::Time(time)) => Bson::Int64(*time),
+            (Field::Trace(_), Payload::Signer(signer)) => {
+                to_bson(signer).map_err(|e| Error::Internal(e.to_string()))?
+            }
+            (Field::Attachments, Payload::Attach(attachment)) => {
+                let attachments: Vec<_> = record.attachments.iter().chain([attachment]).collect();
+
+                to_bson(&attachments).map_err(|e| Error::Internal(e.to_string()))?
+            }
+            (Field::Attachments, Payload::Detach(id)) => {
+                if !record.attachments.iter().any(|listed| listed.id == *id) {
+                    return Err(Error::AttachmentNotFound { id: id.to_string() });
+                }
+                let attachments: Vec<_> = record
+                    .attachments
+                    .iter()
+                    .filter(|listed| listed.id != *id)
+                    .collect();
+
+                to_bson(&attachments).map_err(|e| Error::Internal(e.to_string()))?
+            }
+            (field, payload) => {
+                return Err(Error::Internal(format!(
+                    "{:?} can't be written with {:?}",
+                    field, payload
+                )))
+            }
         };
-        let update = doc! {
-            "$set": {
-                "summary": summary,
+
+        Ok(match field {
+            // traces is null until the collect starts
+            Field::Trace(TraceField::CollectedInside) => doc! {
+                "traces": {
+                    "collected": {
+                        "inside": value,
+                    }
+                },
             },
-        };
+            // traces.returned is null until the return starts
+            Field::Trace(TraceField::ReturnedInside) => doc! {
+                "traces.returned": {
+                    "inside": value,
+                },
+            },
+            field => doc! {
+                field.path(): value,
+            },
+        })
+    }
 
-        self.register
-            .update_one(query, update, None)
+    /// Write a content to a GridFS file, returns its length and sha256
+    async fn write_chunks(
+        upload: &mut GridFsUploadStream,
+        mut data: ByteStream,
+    ) -> Result<(i64, String), Error> {
+        let mut length = 0;
+        let mut hasher = Sha256::new();
+
+        while let Some(chunk) = data.next().await {
+            let chunk = chunk?;
+
+            length += chunk.len() as i64;
+            hasher.update(&chunk);
+            upload
+                .write_all(&chunk)
+                .await
+                .map_err(|e| Error::Internal(e.to_string()))?;
+        }
+
+        upload
+            .close()
             .await
-            .and_then(Mongo::error_on_update_unmatched)
+            .map_err(|e| Error::Internal(e.to_string()))?;
+
+        Ok((length, hex::encode(hasher.finalize())))
     }
 
-    pub(crate) async fn delete_draft(&self, id: StringId) -> Result<DeleteResult, Error> {
-        let query = doc! {
-            "_id": id.to_object_id()?,
-            "state": RecordState::Draft,
+    /// Delete the content of an attachment no longer listed by a record
+    ///
+    /// The record is already updated: a failure only leaves an orphan content.
+    async fn discard_attachment(&self, id: ObjectId) {
+        if let Err(e) = self.attachments.delete(id.into()).await {
+            tracing::warn!("content of attachment {} not deleted: {}", id, e);
+        }
+    }
+
+    /// Bytes of a GridFS file, by chunks
+    fn read_chunks(reader: GridFsDownloadStream) -> ByteStream {
+        let chunks = stream::unfold(Some(reader), |reader| async move {
+            let mut reader = reader?;
+            let mut chunk = vec![0; CHUNK_SIZE];
+
+            match reader.read(&mut chunk).await {
+                Ok(0) => None,
+                Ok(length) => {
+                    chunk.truncate(length);
+                    Some((Ok(chunk), Some(reader)))
+                }
+                // the stream ends after an error
+                Err(e) => Some((Err(Error::Internal(e.to_string())), None)),
+            }
+        });
+
+        Box::pin(chunks)
+    }
+
+    /// Resume token exposed to clients (`_data` of the token document)
+    fn resume_token(token: &ResumeToken) -> String {
+        to_bson(token)
+            .ok()
+            .and_then(|token| {
+                token
+                    .as_document()
+                    .and_then(|token| token.get_str("_data").ok())
+                    .map(str::to_owned)
+            })
+            .unwrap_or_default()
+    }
+
+    fn parse_resume_token(token: &str) -> Result<ResumeToken, Error> {
+        from_bson(Bson::Document(doc! { "_data": token }))
+            .map_err(|_| Error::invalid_argument("resume_token", "malformed token"))
+    }
+
+    /// Error of a change stream starting from a point
+    fn watch_error(e: mongodb::error::Error, point: &str) -> Error {
+        match *e.kind {
+            ErrorKind::Command(ref command)
+                if command.code == CHANGE_STREAM_HISTORY_LOST
+                    || command.code == CHANGE_STREAM_FATAL_ERROR =>
+            {
+                Error::ResumePointExpired(point.to_owned())
+            }
+            _ => e.into(),
+        }
+    }
+
+    fn search_filter(query: &SearchQuery) -> Document {
+        let mut conditions = vec![doc! {"state": { "$in": &query.states }}];
+
+        if let Some(range) = query.range {
+            conditions.push(doc! {"created": { "$gte": range.0 }});
+            conditions.push(doc! {"created": { "$lte": range.1 }});
+        }
+
+        for (field, (begin, end)) in &query.traces {
+            conditions.push(doc! { field.path(): { "$gte": begin, "$lte": end } });
+        }
+
+        // signed by the name on the collect or the return
+        let signed_by = |collected: TraceField, returned: TraceField, name: &String| {
+            let collected = format!("{}.name", collected.path());
+            let returned = format!("{}.name", returned.path());
+
+            doc! { "$or": [{ collected: name }, { returned: name }] }
         };
+        if let Some(name) = &query.client_name {
+            conditions.push(signed_by(
+                TraceField::CollectedClient,
+                TraceField::ReturnedClient,
+                name,
+            ));
+        }
+        if let Some(name) = &query.pqrs_name {
+            conditions.push(signed_by(
+                TraceField::CollectedPqrs,
+                TraceField::ReturnedPqrs,
+                name,
+            ));
+        }
 
-        self.register.delete_one(query, None).await
+        if let Some(text) = &query.text {
+            conditions.push(doc! { "$text": { "$search": text } });
+        }
+
+        doc! { "$and": conditions }
     }
 
-    pub(crate) async fn submit_draft(&self, id: StringId) -> Result<UpdateResult, Error> {
-        let query = doc! {
-            "_id": id.to_object_id()?,
-            "state": RecordState::Draft,
+    fn sort_field(field: SortField) -> Option<&'static str> {
+        match field {
+            SortField::Created => None,
+            SortField::State => Some("state"),
+            SortField::Summary => Some("summary"),
+            // added by the search
+            SortField::Relevance => Some("score"),
+        }
+    }
+
+    /// Sort by field, then creation time and id
+    fn sort_document(sort: Sort) -> Document {
+        let direction = if sort.descending { -1 } else { 1 };
+        let mut document = Document::new();
+
+        if let Some(field) = Mongo::sort_field(sort.field) {
+            document.insert(field, direction);
+        }
+        document.insert("created", direction);
+        document.insert("_id", direction);
+
+        document
+    }
+
+    /// Records after a position: greater sort value, or same value and greater creation time,
+    /// or same value and creation time and greater id
+    fn after_filter(key: &PageKey) -> Document {
+        let operator = if key.descending { "$lt" } else { "$gt" };
+
+        let value = match &key.value {
+            SortValue::None => None,
+            SortValue::State(state) => Some(Bson::Int32(*state)),
+            SortValue::Summary(summary) => Some(Bson::String(summary.clone())),
+            SortValue::Relevance(score) => Some(Bson::Double(*score)),
         };
-        let update = doc! {
-            "$set": {
-                "created": Some(Utc::now().timestamp()),
-                "state": RecordState::Created,
+
+        let mut created_after = doc! { "created": { operator: key.created } };
+        let mut id_after = doc! { "created": key.created, "_id": { operator: key.id } };
+
+        let mut alternatives = vec![];
+        if let (Some(field), Some(value)) = (Mongo::sort_field(key.field), value) {
+            let mut value_after = Document::new();
+            value_after.insert(field, doc! { operator: value.clone() });
+            alternatives.push(value_after);
+
+            created_after.insert(field, value.clone());
+            id_after.insert(field, value);
+        }
+        alternatives.push(created_after);
+        alternatives.push(id_after);
+
+        doc! { "$or": alternatives }
+    }
+
+    /// Search sorted by text score: the score is only known by an aggregation
+    async fn search_by_relevance(&self, query: SearchQuery) -> Result<RecordStream, Error> {
+        let mut pipeline = vec![
+            doc! { "$match": Mongo::search_filter(&query) },
+            doc! { "$addFields": { "score": { "$meta": "textScore" } } },
+        ];
+        if let Some(after) = &query.after {
+            pipeline.push(doc! { "$match": Mongo::after_filter(after) });
+        }
+        pipeline.push(doc! { "$sort": Mongo::sort_document(query.sort) });
+        if let Some(limit) = query.limit {
+            pipeline.push(doc! { "$limit": limit as i64 });
+        }
+
+        let cursor = self.register.aggregate(pipeline, None).await?;
+
+        Ok(Box::pin(cursor.map(|document| {
+            from_document::<Record>(document?).map_err(|e| Error::Internal(e.to_string()))
+        })))
+    }
+
+    fn watch_options() -> ChangeStreamOptions {
+        // max_await_time is the time spent by the server waiting for new events on each getMore.
+        ChangeStreamOptions::builder()
+            .full_document(Some(FullDocumentType::UpdateLookup))
+            .max_await_time(Some(Duration::from_secs(5)))
+            .build()
+    }
+
+    /// Open a change stream keeping the changes passing the filter
+    async fn follow(
+        &self,
+        filter: &WatchFilter,
+        options: ChangeStreamOptions,
+        point: String,
+    ) -> Result<Box<dyn ChangeFeed>, Error> {
+        let change_stream = self
+            .register
+            .watch(Mongo::watch_pipeline(filter)?, Some(options))
+            .await
+            .map_err(|e| Mongo::watch_error(e, &point))?;
+
+        Ok(Box::new(MongoFeed {
+            stream: change_stream,
+            point,
+        }))
+    }
+
+    /// Change stream pipeline keeping the changes passing the filter
+    ///
+    /// Same rules as [WatchFilter::matches], evaluated by the server.
+    fn watch_pipeline(filter: &WatchFilter) -> Result<Vec<Document>, Error> {
+        let mut conditions = vec![];
+
+        if let Some(kinds) = &filter.kinds {
+            let operations: Vec<&str> = kinds
+                .iter()
+                .map(|kind| match kind {
+                    ChangeKind::Added => "insert",
+                    ChangeKind::Modified => "update",
+                    ChangeKind::Deleted => "delete",
+                })
+                .collect();
+
+            conditions.push(doc! { "operationType": { "$in": operations } });
+        }
+
+        if !filter.ids.is_empty() {
+            conditions.push(doc! { "documentKey._id": { "$in": filter.ids.clone() } });
+        }
+
+        if !filter.states.is_empty() {
+            let states = to_bson(&filter.states).map_err(|e| Error::Internal(e.to_string()))?;
+            let state_matches = doc! { "fullDocument.state": { "$in": states } };
+
+            if filter.watches_deletable() {
+                conditions.push(doc! { "$or": [state_matches, { "operationType": "delete" }] });
+            } else {
+                conditions.push(state_matches);
             }
+        }
+
+        if conditions.is_empty() {
+            return Ok(vec![]);
+        }
+
+        Ok(vec![doc! {
+            "$match": {
+                "$or": [{ "operationType": "invalidate" }, { "$and": conditions }]
+            }
+        }])
+    }
+
+    fn to_change(event: ChangeStreamEvent<Record>) -> Option<Change> {
+        match event.operation_type {
+            OperationType::Invalidate => Some(Change::Invalidated),
+            // full document can be missing if the record has been deleted since; the delete event will follow
+            OperationType::Insert => event.full_document.map(Change::Added),
+            OperationType::Update => event.full_document.map(Change::Modified),
+            OperationType::Delete => event
+                .document_key
+                .and_then(|doc| doc.get_object_id("_id").ok())
+                .map(Change::Deleted),
+            _ => None, // skip all other events
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Storage for Mongo {
+    async fn insert_draft(&self, summary: String, origin: Origin) -> Result<ObjectId, Error> {
+        let created = Utc::now().timestamp();
+        let set = doc! {
+            "summary": &summary,
+            "created": created,
+        };
+        let draft = Record {
+            id: None,
+            api_version: API_VERSION_1,
+            created: Some(created),
+            summary,
+            traces: None,
+            state: workflow::INITIAL,
+            transitioned: None,
+            history: vec![self
+                .chain
+                .linked(&[], origin.transition(None, workflow::INITIAL, set))?],
+            seal: None,
+            attachments: vec![],
+            score: None,
         };
 
-        self.register
-            .update_one(query, update, None)
-            .await
-            .and_then(Mongo::error_on_update_unmatched)
+        let result = self.register.insert_one(draft, None).await?;
+
+        result
+            .inserted_id
+            .as_object_id()
+            .ok_or_else(|| Error::Internal("inserted id is not an ObjectId !".to_owned()))
     }
 
-    pub(crate) async fn client_time_trace(
+    async fn update_draft(
         &self,
         id: StringId,
-        time: i64,
-        target: TimeTraceFor,
-    ) -> Result<UpdateResult, Error> {
+        summary: String,
+        origin: Origin,
+    ) -> Result<(), Error> {
+        self.transition(id, Action::UpdateDraft, Payload::Summary(summary), origin)
+            .await
+    }
+
+    async fn delete_draft(&self, id: StringId) -> Result<(), Error> {
+        let id = id.to_object_id()?;
         let query = doc! {
-            "_id": id.to_object_id()?,
-            "state": match target {
-                TimeTraceFor::ClientInsideForCollect => RecordState::Created,                   // Client can collected only after request created
-                TimeTraceFor::ClientOutsideAfterCollect => RecordState::CollectClientSignature, // Client can go out after collect only after signature
-                TimeTraceFor::ClientInsideForReturn => RecordState::CollectPqrsSignature,       // Client can return products only after pqrs signature during collect
-                TimeTraceFor::ClientOutsideAfterReturn => RecordState::ReturnClientSignature,   // Client can go out after return only after signature
-            },
+            "_id": id,
+            "state": { "$in": workflow::DELETABLE.to_vec() },
         };
-        let update = match target {
-            TimeTraceFor::ClientInsideForCollect => doc! {
-                "$set": {
-                    "traces": {
-                        "collected": {
-                            "inside": time,
-                        }
-                    },
-                    "state": RecordState::CollectClientInside,
-                },
-            },
-            TimeTraceFor::ClientOutsideAfterCollect => doc! {
-                "$set": {
-                    "traces.collected.outside": time,
-                    "state": RecordState::CollectClientOutside,
-                },
-            },
-            TimeTraceFor::ClientInsideForReturn => doc! {
-                "$set": {
-                    "traces.returned": {
-                        "inside": time,
-                    },
-                    "state": RecordState::ReturnClientInside,
-                },
-            },
-            TimeTraceFor::ClientOutsideAfterReturn => doc! {
-                "$set": {
-                    "traces.returned.outside": time,
-                    "state": RecordState::ReturnClientOutside,
-                },
-            },
+
+        let Some(record) = self.register.find_one_and_delete(query, None).await? else {
+            return Err(self.unmatched(id, workflow::DELETABLE).await);
         };
 
-        self.register
-            .update_one(query, update, None)
+        for attachment in record.attachments {
+            self.discard_attachment(attachment.id).await;
+        }
+
+        Ok(())
+    }
+
+    async fn submit_draft(&self, id: StringId, origin: Origin) -> Result<(), Error> {
+        self.transition(id, Action::SubmitDraft, Payload::None, origin)
+            .await
+    }
+
+    async fn client_time_trace(
+        &self,
+        id: StringId,
+        time: i64,
+        target: TimeTraceFor,
+        origin: Origin,
+    ) -> Result<(), Error> {
+        self.transition(id, target.into(), Payload::Time(time), origin)
             .await
-            .and_then(Mongo::error_on_update_unmatched)
     }
 
-    pub(crate) async fn signature_trace(
+    async fn signature_trace(
         &self,
         id: StringId,
         signer: Signer,
         target: SignatureTraceFor,
-    ) -> Result<UpdateResult, Error> {
-        let signer = to_bson(&signer)?;
+        origin: Origin,
+    ) -> Result<(), Error> {
+        self.transition(id, target.into(), Payload::Signer(signer), origin)
+            .await
+    }
 
-        let query = doc! {
-            "_id": id.to_object_id()?,
-            "state": match target {
-                SignatureTraceFor::CollectByClient => RecordState::CollectClientInside, // Client can sign only inside office
-                SignatureTraceFor::CollectConfirmedByPqrs => RecordState::CollectClientOutside,  // PQRS can sign only after client go out
-                SignatureTraceFor::ReturnByClient => RecordState::ReturnClientInside,   // Client can sign only inside office
-                SignatureTraceFor::ReturnConfirmedByPqrs => RecordState::ReturnClientOutside,    // PQRS can sign only after client go out
-            },
+    async fn completed(&self, id: StringId, origin: Origin) -> Result<(), Error> {
+        self.transition(id, Action::Complete, Payload::None, origin)
+            .await
+    }
+
+    async fn watch(
+        &self,
+        start: WatchStart,
+        filter: WatchFilter,
+    ) -> Result<Box<dyn ChangeFeed>, Error> {
+        let mut options = Mongo::watch_options();
+
+        let point = match start {
+            WatchStart::Now => String::new(),
+            WatchStart::ResumeAfter(token) => {
+                options.resume_after = Some(Mongo::parse_resume_token(&token)?);
+                token
+            }
+            WatchStart::At(time) => {
+                let time = u32::try_from(time)
+                    .map_err(|_| Error::invalid_argument("start_at", "time out of range"))?;
+                options.start_at_operation_time = Some(Timestamp { time, increment: 0 });
+                time.to_string()
+            }
         };
 
-        let update = match target {
-            SignatureTraceFor::CollectByClient => doc! {
-                "$set": {
-                    "traces.collected.client":  signer,
-                    "state": RecordState::CollectClientSignature,
-                }
-            },
-            SignatureTraceFor::CollectConfirmedByPqrs => doc! {
-                "$set": {
-                    "traces.collected.pqrs": signer,
-                    "state": RecordState::CollectPqrsSignature,
-                }
+        self.follow(&filter, options, point).await
+    }
+
+    async fn snapshot(&self, query: SearchQuery, filter: WatchFilter) -> Result<Snapshot, Error> {
+        // a snapshot session reads all the records at the same cluster time
+        let options = SessionOptions::builder().snapshot(Some(true)).build();
+        let mut session = self.register.client().start_session(Some(options)).await?;
+
+        let mut cursor = self
+            .register
+            .find_with_session(Mongo::search_filter(&query), None, &mut session)
+            .await?;
+        let records: Vec<Record> = cursor
+            .stream(&mut session)
+            .collect::<Result<_, _>>()
+            .await?;
+
+        let read_at = session
+            .operation_time()
+            .ok_or_else(|| Error::Internal("snapshot read without operation time".to_owned()))?;
+
+        // changes at the read time are already in the records
+        let mut options = Mongo::watch_options();
+        options.start_at_operation_time = Some(match read_at.increment.checked_add(1) {
+            Some(increment) => Timestamp {
+                increment,
+                ..read_at
             },
-            SignatureTraceFor::ReturnByClient => doc! {
-                "$set": {
-                    "traces.returned.client": signer,
-                    "state": RecordState::ReturnClientSignature,
-                }
+            None => Timestamp {
+                time: read_at.time + 1,
+                increment: 0,
             },
-            SignatureTraceFor::ReturnConfirmedByPqrs => doc! {
-                "$set": {
-                    "traces.returned.pqrs": signer,
-                    "state": RecordState::ReturnPqrsSignature,
+        });
+
+        Ok(Snapshot {
+            records,
+            feed: self
+                .follow(&filter, options, read_at.time.to_string())
+                .await?,
+        })
+    }
+
+    async fn stats(&self, query: SearchQuery, period: Period) -> Result<Stats, Error> {
+        let unit = match period {
+            Period::Day => "day",
+            Period::Week => "week",
+        };
+
+        let pipeline = vec![
+            doc! { "$match": Mongo::search_filter(&query) },
+            doc! {
+                "$facet": {
+                    "states": [
+                        { "$group": { "_id": "$state", "count": { "$sum": 1 } } },
+                        { "$sort": { "_id": 1 } },
+                    ],
+                    "periods": [
+                        { "$match": { "created": { "$ne": null } } },
+                        {
+                            "$group": {
+                                "_id": {
+                                    "$dateTrunc": {
+                                        "date": { "$toDate": { "$multiply": ["$created", 1000] } },
+                                        "unit": unit,
+                                        "startOfWeek": "monday",
+                                    }
+                                },
+                                "count": { "$sum": 1 },
+                            }
+                        },
+                        { "$sort": { "_id": 1 } },
+                    ],
                 }
             },
+        ];
+
+        let mut cursor = self.register.aggregate(pipeline, None).await?;
+        let Some(facets) = cursor.next().await.transpose()? else {
+            return Ok(Stats::default());
         };
 
-        self.register
-            .update_one(query, update, None)
-            .await
-            .and_then(Mongo::error_on_update_unmatched)
-    }
+        let counts = |facet: &str| -> Result<Vec<(Bson, u64)>, Error> {
+            let groups = facets
+                .get_array(facet)
+                .map_err(|e| Error::Internal(e.to_string()))?;
 
-    pub(crate) async fn completed(&self, id: StringId) -> Result<UpdateResult, Error> {
-        let query = doc! {
-            "_id": id.to_object_id()?,
-            "state": RecordState::ReturnPqrsSignature,
+            Ok(groups
+                .iter()
+                .filter_map(Bson::as_document)
+                .filter_map(|group| {
+                    let count = match group.get("count")? {
+                        Bson::Int32(count) => *count as u64,
+                        Bson::Int64(count) => *count as u64,
+                        _ => return None,
+                    };
+
+                    Some((group.get("_id")?.clone(), count))
+                })
+                .collect())
         };
-        let update = doc! {
-            "$set": {
-                "state": RecordState::Completed,
-            }
+
+        Ok(Stats {
+            states: counts("states")?
+                .into_iter()
+                .filter_map(|(state, count)| Some((RecordState::from_i32(state.as_i32()?)?, count)))
+                .collect(),
+            periods: counts("periods")?
+                .into_iter()
+                .filter_map(|(start, count)| {
+                    Some((start.as_datetime()?.timestamp_millis() / 1000, count))
+                })
+                .collect(),
+        })
+    }
+
+    async fn overdue(&self, limits: Vec<(RecordState, i64)>) -> Result<RecordStream, Error> {
+        if limits.is_empty() {
+            return Ok(Box::pin(tokio_stream::empty()));
+        }
+
+        // records created before the state change tracking only have a creation time
+        let filter = doc! {
+            "$or": limits
+                .into_iter()
+                .map(|(state, limit)| doc! {
+                    "state": state,
+                    "$or": [
+                        { "transitioned": { "$lt": limit } },
+                        { "transitioned": null, "created": { "$lt": limit } },
+                    ],
+                })
+                .collect::<Vec<_>>()
         };
 
-        self.register
-            .update_one(query, update, None)
-            .await
-            .and_then(Mongo::error_on_update_unmatched)
+        let cursor = self.register.find(filter, None).await?;
+
+        Ok(Box::pin(cursor.map(|record| record.map_err(Error::from))))
     }
 
-    pub(crate) async fn watch(&self) -> Result<ChangeStream<ChangeStreamEvent<Record>>, Error> {
-        // max_await_time will have an impact on next_if_any.
-        // be careful as this time will impact when a stream/connection will be closed when a client request is closed.
-        let options = ChangeStreamOptions::builder()
-            .full_document(Some(FullDocumentType::UpdateLookup))
-            .max_await_time(Some(Duration::from_secs(5)))
+    async fn append_audit(&self, entry: AuditEntry) -> Result<(), Error> {
+        self.audit.insert_one(entry, None).await?;
+
+        Ok(())
+    }
+
+    async fn audit(&self, query: AuditQuery) -> Result<AuditStream, Error> {
+        let mut filter = Document::new();
+        if let Some((begin, end)) = query.range {
+            filter.insert("time", doc! { "$gte": begin, "$lte": end });
+        }
+        if let Some(record_id) = query.record_id {
+            filter.insert("record_id", record_id);
+        }
+
+        let options = FindOptions::builder()
+            .sort(doc! { "time": 1, "_id": 1 })
             .build();
+        let cursor = self.audit.find(filter, options).await?;
 
-        self.register.watch(None, Some(options)).await
+        Ok(Box::pin(cursor.map(|entry| entry.map_err(Error::from))))
     }
 
-    pub(crate) async fn search_by_id(&self, id: StringId) -> Result<Option<Record>, Error> {
-        let filter = doc! {
-            "_id": id.to_object_id()?,
+    async fn insert_image(&self, content_type: String, data: Vec<u8>) -> Result<ObjectId, Error> {
+        let options = GridFsUploadOptions::builder()
+            .metadata(doc! { "content_type": content_type })
+            .build();
+
+        Ok(self
+            .signatures
+            .upload_from_futures_0_3_reader("signature", data.as_slice(), options)
+            .await?)
+    }
+
+    async fn delete_image(&self, id: ObjectId) -> Result<(), Error> {
+        Ok(self.signatures.delete(id.into()).await?)
+    }
+
+    async fn image(&self, id: StringId) -> Result<Option<File>, Error> {
+        let id = id.to_object_id()?;
+
+        let mut files = self.signatures.find(doc! { "_id": id }, None).await?;
+        let Some(file) = files.next().await.transpose()? else {
+            return Ok(None);
         };
 
-        self.register.find_one(filter, None).await
+        let content_type = file
+            .metadata
+            .as_ref()
+            .and_then(|metadata| metadata.get_str("content_type").ok())
+            .unwrap_or_default()
+            .to_owned();
+        let reader = self.signatures.open_download_stream(id.into()).await?;
+
+        Ok(Some(File {
+            content_type,
+            data: Mongo::read_chunks(reader),
+        }))
     }
 
-    pub(crate) async fn search(
+    async fn insert_attachment(
         &self,
-        states: Vec<RecordState>,
-        range: Option<(i64, i64)>,
-    ) -> Result<Cursor<Record>, Error> {
-        let filter = match range {
-            None => doc! {
-                "state": { "$in": states }
-            },
-            Some(range) => doc! {
-                "$and" : vec![
-                    doc! {"state": { "$in": states }},
-                    doc! {"created": { "$gte": range.0 }},
-                    doc! {"created": { "$lte": range.1 }},
-                ]
-            },
+        record_id: StringId,
+        filename: String,
+        content_type: String,
+        data: ByteStream,
+        origin: Origin,
+    ) -> Result<Attachment, Error> {
+        let id = record_id.to_object_id()?;
+        let attachable = doc! {
+            "_id": id,
+            "state": { "$in": workflow::ATTACHABLE.to_vec() },
         };
 
-        self.register.find(filter, None).await
+        // fail before reading the content
+        if self.register.count_documents(attachable, None).await? == 0 {
+            return Err(self.unmatched(id, workflow::ATTACHABLE).await);
+        }
+
+        let file = ObjectId::new();
+        let options = GridFsUploadOptions::builder()
+            .metadata(doc! { "record_id": id, "content_type": &content_type })
+            .build();
+        let mut upload =
+            self.attachments
+                .open_upload_stream_with_id(file.into(), &filename, options);
+
+        let (length, sha256) = match Mongo::write_chunks(&mut upload, data).await {
+            Ok(written) => written,
+            Err(e) => {
+                // the chunks already written are removed
+                let _ = upload.abort().await;
+                return Err(e);
+            }
+        };
+
+        let attachment = Attachment {
+            id: file,
+            filename,
+            content_type,
+            length,
+            sha256,
+            uploaded: Utc::now().timestamp(),
+        };
+
+        // the record may have been submitted during the upload
+        let listed = self
+            .transition(
+                record_id,
+                Action::UploadAttachment,
+                Payload::Attach(attachment.clone()),
+                origin,
+            )
+            .await;
+
+        if let Err(e) = listed {
+            self.discard_attachment(file).await;
+            return Err(e);
+        }
+
+        Ok(attachment)
+    }
+
+    async fn delete_attachment(
+        &self,
+        id: StringId,
+        attachment: StringId,
+        origin: Origin,
+    ) -> Result<(), Error> {
+        let attachment = attachment.to_object_id()?;
+
+        self.transition(
+            id,
+            Action::DeleteAttachment,
+            Payload::Detach(attachment),
+            origin,
+        )
+        .await?;
+
+        self.discard_attachment(attachment).await;
+
+        Ok(())
     }
 
-    fn error_on_update_unmatched(result: UpdateResult) -> Result<UpdateResult, Error> {
-        match result.matched_count {
-            0 => Err(Error::custom("no document updated !")),
-            _ => Ok(result),
+    async fn attachment(&self, id: StringId, attachment: StringId) -> Result<Option<File>, Error> {
+        let attachment = attachment.to_object_id()?;
+        let record = self
+            .search_by_id(id.clone())
+            .await?
+            .ok_or_else(|| Error::not_found(&id))?;
+
+        let Some(listed) = record
+            .attachments
+            .into_iter()
+            .find(|listed| listed.id == attachment)
+        else {
+            return Ok(None);
+        };
+
+        let reader = self
+            .attachments
+            .open_download_stream(attachment.into())
+            .await?;
+
+        Ok(Some(File {
+            content_type: listed.content_type,
+            data: Mongo::read_chunks(reader),
+        }))
+    }
+
+    async fn register_key(&self, key: SignerKey) -> Result<(), Error> {
+        let options = ReplaceOptions::builder().upsert(true).build();
+
+        self.keys
+            .replace_one(doc! { "_id": &key.signer }, &key, options)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn signer_key(&self, signer: &str) -> Result<Option<SignerKey>, Error> {
+        Ok(self.keys.find_one(doc! { "_id": signer }, None).await?)
+    }
+
+    async fn search_by_id(&self, id: StringId) -> Result<Option<Record>, Error> {
+        let filter = doc! {
+            "_id": id.to_object_id()?,
+        };
+
+        Ok(self.register.find_one(filter, None).await?)
+    }
+
+    async fn search(&self, query: SearchQuery) -> Result<RecordStream, Error> {
+        if query.sort.field == SortField::Relevance {
+            return self.search_by_relevance(query).await;
+        }
+
+        let mut filter = Mongo::search_filter(&query);
+        if let Some(after) = &query.after {
+            filter = doc! { "$and": [filter, Mongo::after_filter(after)] };
         }
+
+        let options = FindOptions::builder()
+            .sort(Mongo::sort_document(query.sort))
+            .limit(query.limit.map(|limit| limit as i64))
+            .build();
+
+        let cursor = self.register.find(filter, options).await?;
+
+        Ok(Box::pin(cursor.map(|record| record.map_err(Error::from))))
     }
 }
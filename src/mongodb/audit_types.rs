@@ -0,0 +1,21 @@
+use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+
+/// Call of a mutating RPC, kept in the audit collection
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct AuditEntry {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub(crate) id: Option<ObjectId>,
+    /// Server time of the call
+    pub(crate) time: i64,
+    pub(crate) caller: String,
+    pub(crate) rpc: String,
+    /// None if the call doesn't target a record (eg: a failed NewDraft) or was rejected by the
+    /// auth
+    pub(crate) record_id: Option<String>,
+    /// Grpc status code of the outcome, 0 if ok
+    pub(crate) code: i32,
+    pub(crate) message: String,
+    /// Sha256 (hex) of the request payload, protobuf encoded, empty if rejected by the auth
+    pub(crate) digest: String,
+}
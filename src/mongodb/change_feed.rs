@@ -0,0 +1,53 @@
+use std::{
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use mongodb::change_stream::{event::ChangeStreamEvent, ChangeStream};
+use tokio_stream::Stream;
+
+use super::{Mongo, Record};
+use crate::{
+    error::Error,
+    storage::{ChangeEvent, ChangeFeed},
+};
+
+/// Changes of a MongoDB change stream
+pub(crate) struct MongoFeed {
+    pub(crate) stream: ChangeStream<ChangeStreamEvent<Record>>,
+    /// Point the watch started from, reported if it has expired
+    pub(crate) point: String,
+}
+
+impl Stream for MongoFeed {
+    type Item = Result<ChangeEvent, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            let event = match ready!(Pin::new(&mut self.stream).poll_next(cx)) {
+                Some(Ok(event)) => event,
+                Some(Err(e)) => return Poll::Ready(Some(Err(Mongo::watch_error(e, &self.point)))),
+                None => return Poll::Ready(None),
+            };
+
+            let resume_token = Mongo::resume_token(&event.id);
+
+            // skip the events without change for the register
+            if let Some(change) = Mongo::to_change(event) {
+                return Poll::Ready(Some(Ok(ChangeEvent {
+                    change,
+                    resume_token,
+                })));
+            }
+        }
+    }
+}
+
+impl ChangeFeed for MongoFeed {
+    /// Post batch resume token: moves forward even without change
+    fn resume_token(&self) -> Option<String> {
+        self.stream
+            .resume_token()
+            .map(|token| Mongo::resume_token(&token))
+    }
+}
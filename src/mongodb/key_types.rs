@@ -0,0 +1,13 @@
+use serde::{Deserialize, Serialize};
+
+/// Ed25519 public key of a signer, one by signer name
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct SignerKey {
+    #[serde(rename = "_id")]
+    pub(crate) signer: String,
+    /// Hex encoded
+    pub(crate) public_key: String,
+    /// Server time of the registration
+    pub(crate) registered: i64,
+    pub(crate) caller: String,
+}
@@ -1,8 +1,10 @@
-use mongodb::bson::{oid::ObjectId, Bson};
+use mongodb::bson::{oid::ObjectId, Bson, Document};
 use serde::{Deserialize, Serialize};
 use serde_repr::{Deserialize_repr, Serialize_repr};
 
-#[derive(Debug, Deserialize_repr, Serialize_repr, FromPrimitive)]
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize_repr, Serialize_repr, FromPrimitive,
+)]
 #[repr(i32)]
 pub enum RecordState {
     Unspecified = 0,
@@ -25,13 +27,22 @@ impl From<RecordState> for Bson {
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub(crate) struct Signer {
     pub(crate) name: String,
     pub(crate) signature: String,
+    /// Signing time, part of the signed digest
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) signed: Option<i64>,
+    /// Handwritten signature image, stored apart
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) image: Option<ObjectId>,
+    /// Authenticated caller of a PQRS signature, the name is the one declared
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) identity: Option<String>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
 pub(crate) struct Trace {
     pub(crate) inside: Option<i64>,
     pub(crate) outside: Option<i64>,
@@ -39,13 +50,76 @@ pub(crate) struct Trace {
     pub(crate) pqrs: Option<Signer>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
 pub(crate) struct Traces {
     pub(crate) collected: Option<Trace>,
     pub(crate) returned: Option<Trace>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// Phase of a record measured by its traces
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Phase {
+    /// From collected inside to collected outside
+    Collect,
+    /// Products out, from collected outside to returned inside
+    Out,
+    /// From returned inside to returned outside
+    Return,
+}
+
+impl Phase {
+    pub(crate) const ALL: [Phase; 3] = [Phase::Collect, Phase::Out, Phase::Return];
+}
+
+impl Traces {
+    /// Duration of a phase (seconds), none if the phase is not ended or ends before its start
+    pub(crate) fn duration(&self, phase: Phase) -> Option<i64> {
+        let collected = self.collected.as_ref();
+        let returned = self.returned.as_ref();
+
+        let (start, end) = match phase {
+            Phase::Collect => (collected?.inside?, collected?.outside?),
+            Phase::Out => (collected?.outside?, returned?.inside?),
+            Phase::Return => (returned?.inside?, returned?.outside?),
+        };
+
+        (end >= start).then_some(end - start)
+    }
+}
+
+/// A document attached to a draft, its content is stored apart
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub(crate) struct Attachment {
+    pub(crate) id: ObjectId,
+    pub(crate) filename: String,
+    pub(crate) content_type: String,
+    /// Bytes
+    pub(crate) length: i64,
+    /// sha256 (hex) of the content
+    pub(crate) sha256: String,
+    /// Server time of the upload
+    pub(crate) uploaded: i64,
+}
+
+/// Update of a record, kept in its history
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct Transition {
+    /// None on creation
+    pub(crate) from: Option<RecordState>,
+    pub(crate) to: RecordState,
+    /// Server time of the update
+    pub(crate) time: i64,
+    pub(crate) caller: String,
+    pub(crate) rpc: String,
+    /// Fields set by the update, besides the state (`$set` document)
+    #[serde(default)]
+    pub(crate) set: Document,
+    /// Link of the hash chain, missing on entries written before the chain
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) hash: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub(crate) struct Record {
     #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
     pub(crate) id: Option<ObjectId>,
@@ -54,4 +128,73 @@ pub(crate) struct Record {
     pub(crate) summary: String,
     pub(crate) traces: Option<Traces>,
     pub(crate) state: RecordState,
-}
\ No newline at end of file
+    /// Server time of the last state change, missing on records created before
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) transitioned: Option<i64>,
+    /// Every update, oldest first. Empty on records created before
+    #[serde(default)]
+    pub(crate) history: Vec<Transition>,
+    /// Hash of the `Complete` entry, the record can't change after
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) seal: Option<String>,
+    /// Documents attached to the draft, they can't change after
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) attachments: Vec<Attachment>,
+    /// Relevance of the record for a text search, never stored
+    #[serde(default, skip_serializing)]
+    pub(crate) score: Option<f64>,
+}
+
+impl Record {
+    /// Server time the record entered its state
+    pub(crate) fn in_state_since(&self) -> Option<i64> {
+        self.transitioned.or(self.created)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn traces(
+        collected: (Option<i64>, Option<i64>),
+        returned: (Option<i64>, Option<i64>),
+    ) -> Traces {
+        let trace = |(inside, outside)| Trace {
+            inside,
+            outside,
+            ..Default::default()
+        };
+
+        Traces {
+            collected: Some(trace(collected)),
+            returned: Some(trace(returned)),
+        }
+    }
+
+    #[test]
+    fn duration_of_a_phase() {
+        let traces = traces((Some(10), Some(25)), (Some(100), Some(130)));
+
+        assert_eq!(traces.duration(Phase::Collect), Some(15));
+        assert_eq!(traces.duration(Phase::Out), Some(75));
+        assert_eq!(traces.duration(Phase::Return), Some(30));
+    }
+
+    #[test]
+    fn phase_not_ended_has_no_duration() {
+        let traces = traces((Some(10), Some(25)), (None, None));
+
+        assert_eq!(traces.duration(Phase::Out), None);
+        assert_eq!(traces.duration(Phase::Return), None);
+    }
+
+    #[test]
+    fn phase_ending_before_its_start_has_no_duration() {
+        let traces = traces((Some(25), Some(10)), (Some(10), Some(10)));
+
+        assert_eq!(traces.duration(Phase::Collect), None);
+        assert_eq!(traces.duration(Phase::Out), Some(0));
+        assert_eq!(traces.duration(Phase::Return), Some(0));
+    }
+}
@@ -1,12 +1,15 @@
 use core::fmt;
 
-use mongodb::{bson::oid::ObjectId, error::Error};
+use mongodb::bson::oid::ObjectId;
 
+use crate::error::Error;
+
+#[derive(Clone)]
 pub(crate) struct StringId(pub(crate) String);
 
 impl StringId {
     pub(crate) fn to_object_id(&self) -> Result<ObjectId, Error> {
-        ObjectId::parse_str(&self.0).map_err(|e| Error::custom(e))
+        ObjectId::parse_str(&self.0).map_err(|e| Error::invalid_argument("id", e.to_string()))
     }
 }
 
@@ -14,4 +17,4 @@ impl fmt::Display for StringId {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}", self.0)
     }
-}
\ No newline at end of file
+}
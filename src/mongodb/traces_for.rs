@@ -1,3 +1,5 @@
+#[allow(clippy::enum_variant_names)]
+#[derive(Clone, Copy)]
 pub(crate) enum TimeTraceFor {
     ClientInsideForCollect,
     ClientOutsideAfterCollect,
@@ -5,9 +7,20 @@ pub(crate) enum TimeTraceFor {
     ClientOutsideAfterReturn,
 }
 
+#[derive(Clone, Copy)]
 pub(crate) enum SignatureTraceFor {
     CollectByClient,
     CollectConfirmedByPqrs,
     ReturnByClient,
     ReturnConfirmedByPqrs,
-}
\ No newline at end of file
+}
+
+impl SignatureTraceFor {
+    /// Signature of a PQRS agent
+    pub(crate) fn by_pqrs(self) -> bool {
+        matches!(
+            self,
+            SignatureTraceFor::CollectConfirmedByPqrs | SignatureTraceFor::ReturnConfirmedByPqrs
+        )
+    }
+}
@@ -0,0 +1,186 @@
+//! Overdue records
+//!
+//! A record is overdue when it stays in a state longer than the threshold configured for this state
+//! (eg: products out in `CollectPqrsSignature` and never returned). A background task checks the
+//! records periodically and notifies the records becoming overdue.
+
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+use chrono::Utc;
+use mongodb::bson::oid::ObjectId;
+use tokio::{sync::broadcast, time};
+use tokio_stream::StreamExt;
+
+use crate::{
+    error::Error,
+    mongodb::{Record, RecordState},
+    storage::{RecordStream, Storage},
+};
+
+/// Number of notifications kept for slow watchers before they lag
+const EVENTS_CAPACITY: usize = 128;
+
+pub(crate) struct Overdue {
+    db: Arc<dyn Storage>,
+    thresholds: Vec<(RecordState, Duration)>,
+    interval: Duration,
+    events: broadcast::Sender<Record>,
+}
+
+impl Overdue {
+    pub(crate) fn new(
+        db: Arc<dyn Storage>,
+        thresholds: Vec<(RecordState, Duration)>,
+        interval: Duration,
+    ) -> Self {
+        let (events, _) = broadcast::channel(EVENTS_CAPACITY);
+
+        Self {
+            db,
+            thresholds,
+            interval,
+            events,
+        }
+    }
+
+    /// Records overdue now
+    pub(crate) async fn list(&self) -> Result<RecordStream, Error> {
+        let now = Utc::now().timestamp();
+        let limits = self
+            .thresholds
+            .iter()
+            .map(|(state, threshold)| (*state, now - threshold.as_secs() as i64))
+            .collect();
+
+        self.db.overdue(limits).await
+    }
+
+    /// Records becoming overdue from now
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<Record> {
+        self.events.subscribe()
+    }
+
+    /// Check the records periodically, until the service is stopped
+    pub(crate) async fn run(self: Arc<Self>) {
+        if self.thresholds.is_empty() {
+            tracing::info!("overdue check disabled: no threshold");
+            return;
+        }
+
+        let mut interval = time::interval(self.interval);
+        let mut notified = HashSet::new();
+
+        loop {
+            interval.tick().await;
+
+            if let Err(e) = self.check(&mut notified).await {
+                tracing::warn!("overdue check failed: {}", e);
+            }
+        }
+    }
+
+    /// Notify the overdue records not notified by the previous check in the same state
+    async fn check(&self, notified: &mut HashSet<(ObjectId, RecordState)>) -> Result<(), Error> {
+        let mut overdue = self.list().await?;
+        let mut current = HashSet::new();
+
+        while let Some(record) = overdue.next().await {
+            let record = record?;
+            let Some(id) = record.id else {
+                continue;
+            };
+
+            // overdue in its next state too, a record is notified again
+            let key = (id, record.state);
+            current.insert(key);
+            if !notified.contains(&key) {
+                tracing::info!("record {} is overdue in state {:?}", id, record.state);
+
+                // no receiver is not an error; nobody is watching
+                let _ = self.events.send(record);
+            }
+        }
+
+        // a record back in time, then overdue again, is notified again
+        *notified = current;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        chain::Chain,
+        mongodb::{StringId, TimeTraceFor},
+        storage::{Memory, Origin},
+    };
+
+    fn origin() -> Origin {
+        Origin {
+            caller: "test".to_owned(),
+            rpc: "Test",
+        }
+    }
+
+    /// Ids and states of the notified records
+    fn received(events: &mut broadcast::Receiver<Record>) -> Vec<(ObjectId, RecordState)> {
+        let mut received = vec![];
+        while let Ok(record) = events.try_recv() {
+            received.push((record.id.expect("record id"), record.state));
+        }
+
+        received
+    }
+
+    /// Wait for the records to stay in their state longer than a zero threshold, in seconds
+    async fn elapse() {
+        time::sleep(Duration::from_millis(1100)).await;
+    }
+
+    #[tokio::test]
+    async fn overdue_record_is_notified_once_per_state() {
+        let db: Arc<dyn Storage> = Arc::new(Memory::new(Chain::new(b"")));
+        let thresholds = vec![
+            (RecordState::Created, Duration::ZERO),
+            (RecordState::CollectClientInside, Duration::ZERO),
+        ];
+        let overdue = Overdue::new(db.clone(), thresholds, Duration::from_secs(60));
+        let mut events = overdue.subscribe();
+        let mut notified = HashSet::new();
+
+        let id = db
+            .insert_draft("record".to_owned(), origin())
+            .await
+            .expect("draft created");
+        // a draft has no threshold
+        overdue.check(&mut notified).await.expect("checked");
+        assert!(received(&mut events).is_empty());
+
+        db.submit_draft(StringId(id.to_hex()), origin())
+            .await
+            .expect("draft submitted");
+        elapse().await;
+        overdue.check(&mut notified).await.expect("checked");
+        overdue.check(&mut notified).await.expect("checked");
+        assert_eq!(received(&mut events), [(id, RecordState::Created)]);
+
+        // overdue again in the next state before any check saw it in time
+        let now = Utc::now().timestamp();
+        db.client_time_trace(
+            StringId(id.to_hex()),
+            now,
+            TimeTraceFor::ClientInsideForCollect,
+            origin(),
+        )
+        .await
+        .expect("client inside");
+        elapse().await;
+        overdue.check(&mut notified).await.expect("checked");
+        assert_eq!(
+            received(&mut events),
+            [(id, RecordState::CollectClientInside)]
+        );
+    }
+}
@@ -0,0 +1,158 @@
+//! Authorization
+//!
+//! Credentials have roles, and each RPC is allowed to some roles only.
+//! `admin` is allowed every RPC, an RPC missing from the table is allowed to `admin` only.
+
+use std::fmt;
+
+use serde::Deserialize;
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Role {
+    /// Manages the drafts
+    Clerk,
+    /// Records the presence and the signatures of the clients
+    ClientKiosk,
+    /// Signs the PQRS steps and completes the records
+    PqrsAgent,
+    /// Reads the register and its audit trail
+    Auditor,
+    Admin,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Role::Clerk => "clerk",
+            Role::ClientKiosk => "client-kiosk",
+            Role::PqrsAgent => "pqrs-agent",
+            Role::Auditor => "auditor",
+            Role::Admin => "admin",
+        };
+
+        f.write_str(name)
+    }
+}
+
+const CLERK: &[Role] = &[Role::Clerk];
+const KIOSK: &[Role] = &[Role::ClientKiosk];
+const PQRS: &[Role] = &[Role::PqrsAgent];
+const CLOSERS: &[Role] = &[Role::Clerk, Role::PqrsAgent];
+const READERS: &[Role] = &[Role::Clerk, Role::PqrsAgent, Role::Auditor];
+/// Readers of the records, kiosks included to find and follow the records they trace
+const TRACKERS: &[Role] = &[
+    Role::Clerk,
+    Role::ClientKiosk,
+    Role::PqrsAgent,
+    Role::Auditor,
+];
+const AUDITOR: &[Role] = &[Role::Auditor];
+const ADMIN: &[Role] = &[];
+
+/// Roles allowed to call each RPC, besides admin
+static PERMISSIONS: &[(&str, &[Role])] = &[
+    ("NewDraft", CLERK),
+    ("UpdateDraft", CLERK),
+    ("DeleteDraft", CLERK),
+    ("UploadAttachment", CLERK),
+    ("ListAttachments", READERS),
+    ("DownloadAttachment", READERS),
+    ("DeleteAttachment", CLERK),
+    ("SubmitDraft", CLERK),
+    ("CollectClientInside", KIOSK),
+    ("CollectClientSignature", KIOSK),
+    ("CollectClientOutside", KIOSK),
+    ("CollectPqrsSignature", PQRS),
+    ("ReturnClientInside", KIOSK),
+    ("ReturnClientSignature", KIOSK),
+    ("ReturnClientOutside", KIOSK),
+    ("ReturnPqrsSignature", PQRS),
+    ("RegisterSignerKey", ADMIN),
+    ("Complete", CLOSERS),
+    ("Search", TRACKERS),
+    ("SearchById", TRACKERS),
+    ("GetSignatureImage", READERS),
+    ("VerifyRecord", READERS),
+    ("GetRecords", TRACKERS),
+    ("Stats", READERS),
+    ("PhaseDurations", READERS),
+    ("ListOverdue", READERS),
+    ("QueryAudit", AUDITOR),
+    ("Watch", TRACKERS),
+];
+
+/// Check one of the roles is allowed to call the RPC (eg: `Complete`)
+pub(crate) fn allows(roles: &[Role], rpc: &str) -> bool {
+    if roles.contains(&Role::Admin) {
+        return true;
+    }
+
+    PERMISSIONS
+        .iter()
+        .find(|(name, _)| *name == rpc)
+        .is_some_and(|(_, allowed)| roles.iter().any(|role| allowed.contains(role)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// RPCs of the service definition
+    fn rpcs() -> Vec<&'static str> {
+        include_str!("../proto/register.proto")
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix("rpc "))
+            .filter_map(|line| line.split('(').next())
+            .map(str::trim)
+            .collect()
+    }
+
+    #[test]
+    fn every_rpc_has_permissions() {
+        let rpcs = rpcs();
+        assert!(!rpcs.is_empty());
+
+        for rpc in &rpcs {
+            assert!(
+                PERMISSIONS.iter().any(|(name, _)| name == rpc),
+                "{} has no permissions",
+                rpc
+            );
+        }
+        for (name, _) in PERMISSIONS {
+            assert!(rpcs.contains(name), "{} isn't an rpc", name);
+        }
+    }
+
+    #[test]
+    fn roles_allow_their_rpcs() {
+        assert!(allows(&[Role::Clerk], "NewDraft"));
+        assert!(allows(&[Role::Auditor, Role::PqrsAgent], "Complete"));
+        assert!(allows(&[Role::Clerk], "Complete"));
+        assert!(!allows(&[Role::ClientKiosk], "Complete"));
+        assert!(!allows(&[Role::ClientKiosk], "QueryAudit"));
+        assert!(!allows(&[Role::ClientKiosk], "DownloadAttachment"));
+        assert!(!allows(&[], "Search"));
+    }
+
+    #[test]
+    fn kiosk_finds_and_watches_records() {
+        for rpc in ["Search", "SearchById", "GetRecords", "Watch"] {
+            assert!(allows(&[Role::ClientKiosk], rpc), "{}", rpc);
+        }
+    }
+
+    #[test]
+    fn admin_is_allowed_every_rpc() {
+        assert!(allows(&[Role::Admin], "RegisterSignerKey"));
+        assert!(allows(&[Role::Admin], "Complete"));
+        assert!(allows(&[Role::Admin], "Unknown"));
+    }
+
+    #[test]
+    fn unknown_rpc_is_admin_only() {
+        assert!(!allows(&[Role::Clerk], "Unknown"));
+        assert!(!allows(&[Role::Clerk], "RegisterSignerKey"));
+    }
+}
@@ -1,228 +1,656 @@
 //! Register Service (proto)
 
+use std::{collections::HashMap, future, sync::Arc, time::Duration};
+
 use chrono::Utc;
 pub(crate) use internal::register_server::RegisterServer;
 
 use internal::{
-    Draft, EventType, Record, RecordEvent, RecordId, SearchRequest, SignerTrace, TimestampTrace,
-    Traces,
+    Attachment, AttachmentData, AttachmentId, AttachmentUpload, Attachments, AuditEntry,
+    AuditRequest, Draft, EventType, GetRecordsRequest, GetRecordsResponse, Integrity, PeriodCount,
+    Phase, PhaseDurations, PhaseDurationsRequest, PhaseDurationsResponse, Record, RecordEvent,
+    RecordId, SearchRequest, SignatureImage, SignatureImageChunk, SignatureImageId, SignerKey,
+    SignerTrace, SortField, StateCount, StatsPeriod, StatsRequest, StatsResponse, TimestampRange,
+    TimestampTrace, Traces, VerifyRecordResponse, WatchRequest,
 };
-use mongodb::change_stream::event::OperationType;
 use num_traits::FromPrimitive;
 use prost_types::Timestamp;
-use tokio::sync::mpsc;
+use tokio::{
+    sync::{broadcast, mpsc},
+    time::{self, Instant, Interval, MissedTickBehavior},
+};
 use tokio_stream::{wrappers::ReceiverStream, StreamExt};
-use tonic::{Request, Response, Status};
-
-use crate::{config::MongoDbConfig, mongodb as db};
+use tonic::{metadata::MetadataValue, Code, Request, Response, Status, Streaming};
+use tonic_types::{ErrorDetails, StatusExt};
+
+use crate::{
+    attachment,
+    audit::Call,
+    auth::Caller,
+    chain::{self, Chain, Verdict},
+    config::SignerBinding,
+    error::Error,
+    mongodb as db,
+    overdue::Overdue,
+    signature,
+    storage::{
+        self, AuditQuery, ByteStream, Change, ChangeEvent, ChangeKind, Origin, PageKey, Period,
+        SearchQuery, Sort, Storage, WatchFilter, WatchStart,
+    },
+    workflow::{self, TraceField},
+};
 
 #[allow(unreachable_pub)]
 mod internal {
     tonic::include_proto!("register");
 }
 
+static ERROR_DOMAIN: &str = "register.encelade";
+static RECORD_RESOURCE: &str = "register.Record";
+static SIGNATURE_IMAGE_RESOURCE: &str = "register.SignatureImage";
+static ATTACHMENT_RESOURCE: &str = "register.Attachment";
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_GET_RECORDS: usize = 100;
+const MAX_PAGE_SIZE: usize = 1000;
+/// Response metadata with the token of the next page of a search
+static NEXT_PAGE_TOKEN: &str = "next-page-token";
+
 pub(crate) struct Register {
-    db: db::Mongo,
+    db: Arc<dyn Storage>,
+    /// Interval of the watch heartbeats, disabled if none
+    heartbeat: Option<Duration>,
+    overdue: Arc<Overdue>,
+    /// Verify the signer signatures with their registered keys
+    verify_signatures: bool,
+    /// Reject the PQRS signers other than the caller
+    pqrs_signer: SignerBinding,
+    chain: Chain,
 }
 
 #[tonic::async_trait]
 impl internal::register_server::Register for Register {
     async fn new_draft(&self, request: Request<Draft>) -> Result<Response<RecordId>, Status> {
+        // the id of the request is ignored, the record is known once created
+        let mut call = Register::call(&request, "NewDraft", "");
         let request = request.into_inner();
 
         tracing::info!("new draft request");
 
-        self.db
-            .insert_draft(request.summary)
+        let result = self
+            .db
+            .insert_draft(request.summary, call.origin.clone())
             .await
-            .map(|result| {
-                Response::new(RecordId {
-                    id: result.inserted_id.to_string(),
-                })
-            })
-            .map_err(|e| Status::aborted(e.to_string()))
+            .map(|id| Response::new(RecordId { id: id.to_string() }))
+            .map_err(Status::from);
+
+        if let Ok(response) = &result {
+            call.on_record(response.get_ref().id.clone());
+        }
+
+        self.audited(call, result).await
     }
 
     async fn update_draft(&self, request: Request<Draft>) -> Result<Response<()>, Status> {
+        let call = Register::call(&request, "UpdateDraft", &request.get_ref().id);
         let request = request.into_inner();
 
         tracing::info!("update draft request for {}", request.id);
 
-        self.db
-            .update_draft(db::StringId(request.id), request.summary)
+        let result = self
+            .db
+            .update_draft(
+                db::StringId(request.id),
+                request.summary,
+                call.origin.clone(),
+            )
             .await
             .map(|_| Register::empty_response())
-            .map_err(|e| Status::aborted(e.to_string()))
+            .map_err(Status::from);
+
+        self.audited(call, result).await
     }
 
     async fn delete_draft(&self, request: Request<RecordId>) -> Result<Response<()>, Status> {
+        let call = Register::call(&request, "DeleteDraft", &request.get_ref().id);
         let request = request.into_inner();
 
         tracing::info!("delete draft request for {}", request.id);
 
-        self.db
+        let result = self
+            .db
             .delete_draft(db::StringId(request.id))
             .await
             .map(|_| Register::empty_response())
-            .map_err(|e| Status::aborted(e.to_string()))
+            .map_err(Status::from);
+
+        self.audited(call, result).await
+    }
+
+    async fn upload_attachment(
+        &self,
+        request: Request<Streaming<AttachmentUpload>>,
+    ) -> Result<Response<Attachment>, Status> {
+        let origin = Register::origin(&request, "UploadAttachment");
+        let mut upload = request.into_inner();
+
+        let first = match upload.message().await {
+            Ok(Some(message)) => Ok(message),
+            Ok(None) => Err(Error::invalid_argument(
+                "record_id",
+                "the first message describes the attachment",
+            )
+            .into()),
+            Err(status) => Err(status),
+        };
+        let AttachmentUpload {
+            record_id,
+            filename,
+            content_type,
+            data,
+        } = match first {
+            Ok(message) => message,
+            // audited without the description of the attachment
+            Err(status) => {
+                let call = Call::new(origin, &AttachmentUpload::default(), "");
+                return self.audited(call, Err(status)).await;
+            }
+        };
+
+        tracing::info!("upload attachment request for {}", record_id);
+
+        // the content is audited by its sha256
+        let description = AttachmentUpload {
+            record_id: record_id.clone(),
+            filename: filename.clone(),
+            content_type: content_type.clone(),
+            data: vec![],
+        };
+        let mut call = Call::new(origin, &description, &record_id);
+
+        let chunks = tokio_stream::once(Ok(data)).chain(upload.map(|message| {
+            message.map(|message| message.data).map_err(|status| {
+                Error::invalid_argument("data", format!("upload interrupted: {}", status.message()))
+            })
+        }));
+
+        let result = match attachment::check(&filename, &content_type) {
+            Ok(()) => {
+                self.db
+                    .insert_attachment(
+                        db::StringId(record_id),
+                        filename,
+                        content_type,
+                        attachment::content(chunks),
+                        call.origin.clone(),
+                    )
+                    .await
+            }
+            Err(e) => Err(e),
+        }
+        .map(|attachment| {
+            call.on_content(attachment.sha256.clone());
+            Response::new(attachment.into())
+        })
+        .map_err(Status::from);
+
+        self.audited(call, result).await
+    }
+
+    async fn list_attachments(
+        &self,
+        request: Request<RecordId>,
+    ) -> Result<Response<Attachments>, Status> {
+        let request = request.into_inner();
+
+        tracing::info!("list attachments request for {}", request.id);
+
+        let record = self
+            .db
+            .search_by_id(db::StringId(request.id.clone()))
+            .await?
+            .ok_or_else(|| Error::not_found(request.id))?;
+
+        Ok(Response::new(Attachments {
+            attachments: record
+                .attachments
+                .into_iter()
+                .map(|attachment| attachment.into())
+                .collect(),
+        }))
+    }
+
+    type DownloadAttachmentStream = ReceiverStream<Result<AttachmentData, Status>>;
+
+    async fn download_attachment(
+        &self,
+        request: Request<AttachmentId>,
+    ) -> Result<Response<Self::DownloadAttachmentStream>, Status> {
+        let request = request.into_inner();
+
+        tracing::info!(
+            "download attachment request for {} of {}",
+            request.id,
+            request.record_id
+        );
+
+        let file = self
+            .db
+            .attachment(
+                db::StringId(request.record_id),
+                db::StringId(request.id.clone()),
+            )
+            .await?
+            .ok_or(Error::AttachmentNotFound { id: request.id })?;
+
+        let chunks = Register::send_chunks("download attachment", file.data, |data| {
+            AttachmentData { data }
+        });
+
+        Ok(Response::new(chunks))
+    }
+
+    async fn delete_attachment(
+        &self,
+        request: Request<AttachmentId>,
+    ) -> Result<Response<()>, Status> {
+        let call = Register::call(&request, "DeleteAttachment", &request.get_ref().record_id);
+        let request = request.into_inner();
+
+        tracing::info!(
+            "delete attachment request for {} of {}",
+            request.id,
+            request.record_id
+        );
+
+        let result = self
+            .db
+            .delete_attachment(
+                db::StringId(request.record_id),
+                db::StringId(request.id),
+                call.origin.clone(),
+            )
+            .await
+            .map(|_| Register::empty_response())
+            .map_err(Status::from);
+
+        self.audited(call, result).await
     }
 
     async fn submit_draft(&self, request: Request<RecordId>) -> Result<Response<()>, Status> {
+        let call = Register::call(&request, "SubmitDraft", &request.get_ref().id);
         let request = request.into_inner();
 
         tracing::info!("submit draft request for {}", request.id);
 
-        self.db
-            .submit_draft(db::StringId(request.id))
+        let result = self
+            .db
+            .submit_draft(db::StringId(request.id), call.origin.clone())
             .await
             .map(|_| Register::empty_response())
-            .map_err(|e| Status::aborted(e.to_string()))
+            .map_err(Status::from);
+
+        self.audited(call, result).await
     }
 
     async fn collect_client_inside(
         &self,
         request: Request<TimestampTrace>,
     ) -> Result<Response<()>, Status> {
-        self.client_time_trace(request, db::TimeTraceFor::ClientInsideForCollect)
-            .await
+        self.client_time_trace(
+            request,
+            db::TimeTraceFor::ClientInsideForCollect,
+            "CollectClientInside",
+        )
+        .await
     }
 
     async fn collect_client_signature(
         &self,
         request: Request<SignerTrace>,
     ) -> Result<Response<()>, Status> {
-        self.signature_trace(request, db::SignatureTraceFor::CollectByClient)
-            .await
+        self.signature_trace(
+            request,
+            db::SignatureTraceFor::CollectByClient,
+            "CollectClientSignature",
+        )
+        .await
     }
 
     async fn collect_client_outside(
         &self,
         request: Request<TimestampTrace>,
     ) -> Result<Response<()>, Status> {
-        self.client_time_trace(request, db::TimeTraceFor::ClientOutsideAfterCollect)
-            .await
+        self.client_time_trace(
+            request,
+            db::TimeTraceFor::ClientOutsideAfterCollect,
+            "CollectClientOutside",
+        )
+        .await
     }
 
     async fn collect_pqrs_signature(
         &self,
         request: Request<SignerTrace>,
     ) -> Result<Response<()>, Status> {
-        self.signature_trace(request, db::SignatureTraceFor::CollectConfirmedByPqrs)
-            .await
+        self.signature_trace(
+            request,
+            db::SignatureTraceFor::CollectConfirmedByPqrs,
+            "CollectPqrsSignature",
+        )
+        .await
     }
 
     async fn return_client_inside(
         &self,
         request: Request<TimestampTrace>,
     ) -> Result<Response<()>, Status> {
-        self.client_time_trace(request, db::TimeTraceFor::ClientInsideForReturn)
-            .await
+        self.client_time_trace(
+            request,
+            db::TimeTraceFor::ClientInsideForReturn,
+            "ReturnClientInside",
+        )
+        .await
     }
 
     async fn return_client_signature(
         &self,
         request: Request<SignerTrace>,
     ) -> Result<Response<()>, Status> {
-        self.signature_trace(request, db::SignatureTraceFor::ReturnByClient)
-            .await
+        self.signature_trace(
+            request,
+            db::SignatureTraceFor::ReturnByClient,
+            "ReturnClientSignature",
+        )
+        .await
     }
 
     async fn return_client_outside(
         &self,
         request: Request<TimestampTrace>,
     ) -> Result<Response<()>, Status> {
-        self.client_time_trace(request, db::TimeTraceFor::ClientOutsideAfterReturn)
-            .await
+        self.client_time_trace(
+            request,
+            db::TimeTraceFor::ClientOutsideAfterReturn,
+            "ReturnClientOutside",
+        )
+        .await
     }
 
     async fn return_pqrs_signature(
         &self,
         request: Request<SignerTrace>,
     ) -> Result<Response<()>, Status> {
-        self.signature_trace(request, db::SignatureTraceFor::ReturnConfirmedByPqrs)
-            .await
+        self.signature_trace(
+            request,
+            db::SignatureTraceFor::ReturnConfirmedByPqrs,
+            "ReturnPqrsSignature",
+        )
+        .await
     }
 
     async fn complete(&self, request: Request<RecordId>) -> Result<Response<()>, Status> {
+        let call = Register::call(&request, "Complete", &request.get_ref().id);
         let request = request.into_inner();
 
         tracing::info!("complete request for {}", request.id);
 
-        self.db
-            .completed(db::StringId(request.id))
+        let result = self
+            .db
+            .completed(db::StringId(request.id), call.origin.clone())
             .await
             .map(|_| Register::empty_response())
-            .map_err(|e| Status::aborted(e.to_string()))
+            .map_err(Status::from);
+
+        self.audited(call, result).await
+    }
+
+    type QueryAuditStream = ReceiverStream<Result<AuditEntry, Status>>;
+
+    async fn query_audit(
+        &self,
+        request: Request<AuditRequest>,
+    ) -> Result<Response<Self::QueryAuditStream>, Status> {
+        tracing::info!("query audit request");
+
+        let request = request.into_inner();
+
+        let query = AuditQuery {
+            range: request.range.and_then(Register::time_range),
+            record_id: request.record_id,
+        };
+
+        let mut cursor = self.db.audit(query).await.map_err(Status::from)?;
+
+        let (tx, rx) = mpsc::channel::<Result<AuditEntry, Status>>(10);
+
+        tokio::spawn(async move {
+            while let Some(entry) = cursor.next().await {
+                let entry = entry.map(|res| res.into()).map_err(Status::from);
+
+                if tx.send(entry).await.is_err() {
+                    tracing::info!("query audit closed by client");
+                    return;
+                }
+            }
+
+            tracing::debug!("query audit closed");
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    type GetSignatureImageStream = ReceiverStream<Result<SignatureImageChunk, Status>>;
+
+    async fn get_signature_image(
+        &self,
+        request: Request<SignatureImageId>,
+    ) -> Result<Response<Self::GetSignatureImageStream>, Status> {
+        let request = request.into_inner();
+
+        tracing::info!("get signature image request for {}", request.id);
+
+        let file = self
+            .db
+            .image(db::StringId(request.id.clone()))
+            .await?
+            .ok_or(Error::ImageNotFound { id: request.id })?;
+
+        let mut content_type = file.content_type;
+        let chunks = Register::send_chunks("get signature image", file.data, move |data| {
+            SignatureImageChunk {
+                content_type: std::mem::take(&mut content_type),
+                data,
+            }
+        });
+
+        Ok(Response::new(chunks))
     }
 
     type WatchStream = ReceiverStream<Result<RecordEvent, Status>>;
 
-    async fn watch(&self, _request: Request<()>) -> Result<Response<Self::WatchStream>, Status> {
+    async fn watch(
+        &self,
+        request: Request<WatchRequest>,
+    ) -> Result<Response<Self::WatchStream>, Status> {
         tracing::info!("watch request");
 
-        let mut change_stream = self
-            .db
-            .watch()
-            .await
-            .map_err(|e| Status::aborted(e.to_string()))?;
+        let request = request.into_inner();
+
+        let start = match (request.resume_token, request.start_at) {
+            (None, None) => WatchStart::Now,
+            (Some(token), None) => WatchStart::ResumeAfter(token),
+            (None, Some(start_at)) => WatchStart::At(start_at.seconds),
+            (Some(_), Some(_)) => {
+                return Err(Error::invalid_argument(
+                    "start_at",
+                    "resume_token and start_at can't be used together",
+                )
+                .into())
+            }
+        };
+
+        let event_types = request
+            .event_types
+            .iter()
+            .map(|event_type| match EventType::try_from(*event_type) {
+                Ok(
+                    event_type @ (EventType::Added
+                    | EventType::Modified
+                    | EventType::Deleted
+                    | EventType::Overdue),
+                ) => Ok(event_type),
+                _ => Err(Error::invalid_argument(
+                    "event_types",
+                    format!("{} can't be watched", event_type),
+                )),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // no event type filter watches every event
+        let watch_overdue = event_types.is_empty() || event_types.contains(&EventType::Overdue);
+        let kinds = (!event_types.is_empty()).then(|| {
+            event_types
+                .iter()
+                .filter_map(|event_type| match event_type {
+                    EventType::Added => Some(ChangeKind::Added),
+                    EventType::Modified => Some(ChangeKind::Modified),
+                    EventType::Deleted => Some(ChangeKind::Deleted),
+                    _ => None,
+                })
+                .collect()
+        });
+
+        let filter = WatchFilter {
+            states: request
+                .states
+                .iter()
+                .map(|i| {
+                    FromPrimitive::from_i32(i.to_owned()).unwrap_or(db::RecordState::Unspecified)
+                })
+                .collect(),
+            ids: request
+                .ids
+                .into_iter()
+                .map(|id| db::StringId(id).to_object_id())
+                .collect::<Result<_, _>>()?,
+            kinds,
+        };
+        let overdue_filter = filter.clone();
+
+        let (mut changes, snapshot) = match request.snapshot {
+            None => (
+                self.db.watch(start, filter).await.map_err(Status::from)?,
+                None,
+            ),
+            Some(_) if !matches!(start, WatchStart::Now) => {
+                return Err(Error::invalid_argument(
+                    "snapshot",
+                    "snapshot can't be used with resume_token or start_at",
+                )
+                .into())
+            }
+            Some(search) => {
+                // the whole search is sent, without pagination
+                let query = SearchQuery {
+                    after: None,
+                    ..Register::search_query(search)?
+                };
+
+                let snapshot = self
+                    .db
+                    .snapshot(query, filter)
+                    .await
+                    .map_err(Status::from)?;
+
+                (snapshot.feed, Some(snapshot.records))
+            }
+        };
 
         let (tx, rx) = mpsc::channel::<Result<RecordEvent, Status>>(10);
 
+        let mut heartbeat = self.heartbeat.map(|period| {
+            let mut heartbeat = time::interval_at(Instant::now() + period, period);
+            heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            heartbeat
+        });
+
+        let mut overdue = watch_overdue.then(|| self.overdue.subscribe());
+
         // ready to spawn
         tokio::spawn(async move {
-            // loop if the stream is alive or the tx is not closed due to a request ended by the client (rx side)
-            while change_stream.is_alive() && !tx.is_closed() {
-                if let Some(change_stream_event) = change_stream.next_if_any().await.transpose() {
-                    let event = match change_stream_event {
-                        Ok(change_stream_event) => match change_stream_event.operation_type {
-                            OperationType::Invalidate => Err(Status::cancelled(
-                                "A global issue with the collection occurs on the database side",
-                            )),
-                            OperationType::Insert => Ok(RecordEvent {
-                                event_type: EventType::Added as i32,
-                                record: change_stream_event.full_document.map(|doc| doc.into()),
-                            }),
-                            OperationType::Update => Ok(RecordEvent {
-                                event_type: EventType::Modified as i32,
-                                record: change_stream_event.full_document.map(|doc| doc.into()),
-                            }),
-                            OperationType::Delete => {
-                                let doc = change_stream_event.document_key;
-                                let id = doc.map_or_else(
-                                    || "".to_owned(),
-                                    |doc| {
-                                        doc.get_object_id("_id")
-                                            .map_or_else(|_| "".to_owned(), |id| id.to_string())
-                                    },
-                                );
-
-                                let record = Some(Record {
-                                    id,
-                                    api_version: 0,
-                                    summary: "".to_owned(),
-                                    created: None,
-                                    traces: None,
-                                    state: 0,
-                                });
-
-                                Ok(RecordEvent {
-                                    event_type: EventType::Deleted as i32,
-                                    record,
-                                })
-                            }
-                            _ => continue, // skip all other events
-                        },
-                        Err(e) => Err(Status::aborted(e.to_string())),
-                    };
-
-                    if let Err(_) = tx.send(event).await {
+            // the snapshot is sent before any change
+            if let Some(records) = snapshot {
+                let resume_token = changes.resume_token().unwrap_or_default();
+
+                for event in snapshot_events(records, resume_token) {
+                    if tx.send(Ok(event)).await.is_err() {
+                        tracing::info!("watch closed by client");
+                        return;
+                    }
+                }
+            }
+
+            loop {
+                // stop as soon as the request is ended by the client (rx side)
+                let change = tokio::select! {
+                    _ = tx.closed() => {
                         tracing::info!("watch closed by client");
                         return;
                     }
+                    change = changes.next() => change,
+                    record = Register::next_overdue(&mut overdue) => {
+                        if !overdue_filter.matches_record(record.id, Some(record.state)) {
+                            continue;
+                        }
+
+                        let event = RecordEvent {
+                            event_type: EventType::Overdue as i32,
+                            record: Some(record.into()),
+                            resume_token: changes.resume_token().unwrap_or_default(),
+                            server_time: Some(Register::now()),
+                        };
+
+                        if tx.send(Ok(event)).await.is_err() {
+                            tracing::info!("watch closed by client");
+                            return;
+                        }
+                        continue;
+                    }
+                    _ = Register::tick(&mut heartbeat) => {
+                        let event = RecordEvent {
+                            event_type: EventType::Heartbeat as i32,
+                            record: None,
+                            resume_token: changes.resume_token().unwrap_or_default(),
+                            server_time: Some(Register::now()),
+                        };
+
+                        if tx.send(Ok(event)).await.is_err() {
+                            tracing::info!("watch closed by client");
+                            return;
+                        }
+                        continue;
+                    }
+                };
+
+                // db stream closed
+                let Some(change) = change else {
+                    break;
+                };
+
+                let event = change.map_err(Status::from).and_then(to_record_event);
+
+                // a silent watch only needs heartbeats
+                if let Some(heartbeat) = heartbeat.as_mut() {
+                    heartbeat.reset();
+                }
+
+                if tx.send(event).await.is_err() {
+                    tracing::info!("watch closed by client");
+                    return;
                 }
             }
 
-            // watch end due to tx.send error or rx closed by client side or db stream closed
+            // watch end due to db stream closed
             tracing::debug!("watch closed");
         });
 
@@ -239,46 +667,40 @@ impl internal::register_server::Register for Register {
 
         let request = request.into_inner();
 
-        let states = request
-            .states
-            .iter()
-            .map(|i| FromPrimitive::from_i32(i.to_owned()).unwrap_or(db::RecordState::Unspecified))
-            .collect();
+        let page_size = match request.page_size {
+            0 => None,
+            size => Some((size as usize).min(MAX_PAGE_SIZE)),
+        };
 
-        let range = request
-            .range
-            .map(|range| {
-                let mut rangefilter = (0, 0);
-
-                if let Some(begin) = range.begin {
-                    rangefilter.0 = begin.seconds;
-                    rangefilter.1 = match range.end {
-                        Some(end) => end.seconds,
-                        None => Utc::now().timestamp(),
-                    };
-
-                    Some(rangefilter)
-                } else {
-                    None
-                }
-            })
-            .flatten();
+        let mut query = Register::search_query(request)?;
+        let sort = query.sort;
+        // one more record tells if there is a next page
+        query.limit = page_size.map(|size| size + 1);
 
-        let mut cursor = self
-            .db
-            .search(states, range)
-            .await
-            .map_err(|e| Status::aborted(e.to_string()))?;
+        let mut cursor = self.db.search(query).await.map_err(Status::from)?;
+
+        let mut next_page_token = None;
+        if let Some(size) = page_size {
+            let mut page = Vec::with_capacity(size + 1);
+            while let Some(record) = cursor.next().await {
+                page.push(record.map_err(Status::from)?);
+            }
+
+            if page.len() > size {
+                page.truncate(size);
+                next_page_token = page.last().map(|record| sort.key(record).to_token());
+            }
+
+            cursor = Box::pin(tokio_stream::iter(page).map(Ok));
+        }
 
         let (tx, rx) = mpsc::channel::<Result<Record, Status>>(10);
 
         tokio::spawn(async move {
             while let Some(doc) = cursor.next().await {
-                let doc = doc
-                    .map(|res| res.into())
-                    .map_err(|e| Status::aborted(e.to_string()));
+                let doc = doc.map(|res| res.into()).map_err(Status::from);
 
-                if let Err(_) = tx.send(doc).await {
+                if tx.send(doc).await.is_err() {
                     tracing::info!("search closed by client");
                     return;
                 }
@@ -287,7 +709,14 @@ impl internal::register_server::Register for Register {
             tracing::debug!("search closed");
         });
 
-        Ok(Response::new(ReceiverStream::new(rx)))
+        let mut response = Response::new(ReceiverStream::new(rx));
+        if let Some(token) = next_page_token {
+            let token =
+                MetadataValue::try_from(token).map_err(|e| Status::internal(e.to_string()))?;
+            response.metadata_mut().insert(NEXT_PAGE_TOKEN, token);
+        }
+
+        Ok(response)
     }
 
     async fn search_by_id(&self, request: Request<RecordId>) -> Result<Response<Record>, Status> {
@@ -295,125 +724,1525 @@ impl internal::register_server::Register for Register {
 
         tracing::info!("search request for {}", request.id);
 
-        self.db
-            .search_by_id(db::StringId(request.id))
-            .await
-            .map(|result| {
-                Response::new(result.map_or(
-                    // No record found with this id
-                    Record {
-                        id: "".to_owned(),
-                        api_version: 0,
-                        summary: "".to_owned(),
-                        created: None,
-                        traces: None,
-                        state: 0,
-                    },
-                    |result| result.into(),
-                ))
-            })
-            .map_err(|e| Status::aborted(e.to_string()))
-    }
-}
-
-impl Register {
-    pub(crate) async fn new(config: MongoDbConfig) -> Result<Self, mongodb::error::Error> {
-        Ok(Self {
-            db: db::Mongo::new(config).await?,
-        })
-    }
+        let id = db::StringId(request.id);
 
-    fn empty_response() -> Response<()> {
-        Response::new(())
+        match self.db.search_by_id(id.clone()).await? {
+            Some(record) => Ok(Response::new(record.into())),
+            None => Err(Error::not_found(&id).into()),
+        }
     }
 
-    async fn client_time_trace(
+    async fn register_signer_key(
         &self,
-        request: Request<TimestampTrace>,
-        target: db::TimeTraceFor,
+        request: Request<SignerKey>,
     ) -> Result<Response<()>, Status> {
+        let call = Register::call(&request, "RegisterSignerKey", "");
         let request = request.into_inner();
 
-        tracing::info!("trace request for {}", request.id);
-
-        let time = request.time.map_or(0, |time| time.seconds);
+        tracing::info!("register key request for {}", request.signer_name);
+
+        let result = match signature::parse_key(&request.public_key) {
+            _ if request.signer_name.is_empty() => Err(Error::invalid_argument(
+                "signer_name",
+                "signer name is required",
+            )),
+            Err(e) => Err(e),
+            Ok(_) => {
+                let key = db::SignerKey {
+                    signer: request.signer_name,
+                    public_key: hex::encode(&request.public_key),
+                    registered: Utc::now().timestamp(),
+                    caller: call.origin.caller.clone(),
+                };
+
+                self.db.register_key(key).await
+            }
+        }
+        .map(|_| Register::empty_response())
+        .map_err(Status::from);
 
-        self.db
-            .client_time_trace(db::StringId(request.id), time, target)
-            .await
-            .map(|_| Register::empty_response())
-            .map_err(|e| Status::aborted(e.to_string()))
+        self.audited(call, result).await
     }
 
-    async fn signature_trace(
+    async fn verify_record(
         &self,
-        request: Request<SignerTrace>,
-        target: db::SignatureTraceFor,
-    ) -> Result<Response<()>, Status> {
+        request: Request<RecordId>,
+    ) -> Result<Response<VerifyRecordResponse>, Status> {
         let request = request.into_inner();
 
-        tracing::info!("sign request for {}", request.id);
+        tracing::info!("verify request for {}", request.id);
 
-        let signer = request
-            .signer
-            .ok_or(Status::invalid_argument("Signer is required !"))?;
+        let id = db::StringId(request.id);
 
-        let signer = db::Signer {
-            name: signer.name,
-            signature: signer.signature,
+        let Some(record) = self.db.search_by_id(id.clone()).await? else {
+            return Err(Error::not_found(&id).into());
         };
 
-        self.db
-            .signature_trace(db::StringId(request.id), signer, target)
-            .await
-            .map(|_| Register::empty_response())
-            .map_err(|e| Status::aborted(e.to_string()))
+        let (integrity, altered_entry, reason) = match self.chain.verify(&record) {
+            Verdict::Intact => (Integrity::Intact, None, ""),
+            Verdict::Altered { entry, reason } => {
+                tracing::warn!("record {} altered: {}", id, reason);
+                (Integrity::Altered, entry.map(|entry| entry as u32), reason)
+            }
+            Verdict::PartiallyChained => (Integrity::PartiallyChained, None, ""),
+            Verdict::Unchained => (Integrity::Unchained, None, ""),
+        };
+
+        Ok(Response::new(VerifyRecordResponse {
+            integrity: integrity as i32,
+            altered_entry,
+            reason: reason.to_owned(),
+            hash: chain::last_hash(&record.history).to_owned(),
+            sealed: record.seal.is_some(),
+        }))
     }
-}
 
-impl From<crate::mongodb::Record> for internal::Record {
-    fn from(value: crate::mongodb::Record) -> Self {
-        let created = value.created.map(|created| Timestamp {
-            seconds: created,
-            nanos: 0,
-        });
-        let traces = value.traces.map(|traces| Traces {
-            collected: traces.collected.map(|trace| trace.into()),
-            returned: traces.returned.map(|trace| trace.into()),
+    type ListOverdueStream = ReceiverStream<Result<Record, Status>>;
+
+    async fn list_overdue(
+        &self,
+        _request: Request<()>,
+    ) -> Result<Response<Self::ListOverdueStream>, Status> {
+        tracing::info!("list overdue request");
+
+        let mut cursor = self.overdue.list().await.map_err(Status::from)?;
+
+        let (tx, rx) = mpsc::channel::<Result<Record, Status>>(10);
+
+        tokio::spawn(async move {
+            while let Some(doc) = cursor.next().await {
+                let doc = doc.map(|res| res.into()).map_err(Status::from);
+
+                if tx.send(doc).await.is_err() {
+                    tracing::info!("list overdue closed by client");
+                    return;
+                }
+            }
+
+            tracing::debug!("list overdue closed");
         });
 
-        Self {
-            id: value.id.unwrap().to_string(),
-            api_version: value.api_version,
-            created,
-            summary: value.summary,
-            traces,
-            state: value.state as i32,
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn get_records(
+        &self,
+        request: Request<GetRecordsRequest>,
+    ) -> Result<Response<GetRecordsResponse>, Status> {
+        let request = request.into_inner();
+
+        tracing::info!("get records request for {} ids", request.ids.len());
+
+        if request.ids.len() > MAX_GET_RECORDS {
+            return Err(Error::invalid_argument(
+                "ids",
+                format!("at most {} ids can be requested", MAX_GET_RECORDS),
+            )
+            .into());
+        }
+
+        let mut response = GetRecordsResponse {
+            records: Vec::with_capacity(request.ids.len()),
+            missing_ids: vec![],
+            invalid_ids: vec![],
+        };
+
+        for id in request.ids {
+            let id = db::StringId(id.id);
+
+            // a malformed id doesn't fail the whole batch
+            if id.to_object_id().is_err() {
+                response.invalid_ids.push(id.0);
+                continue;
+            }
+
+            match self.db.search_by_id(id.clone()).await? {
+                Some(record) => response.records.push(record.into()),
+                None => response.missing_ids.push(id.0),
+            }
         }
+
+        Ok(Response::new(response))
     }
-}
 
-impl From<crate::mongodb::Trace> for internal::Trace {
-    fn from(value: crate::mongodb::Trace) -> Self {
-        let to_timestamp = |time: i64| Timestamp {
-            seconds: time,
-            nanos: 0,
+    async fn stats(
+        &self,
+        request: Request<StatsRequest>,
+    ) -> Result<Response<StatsResponse>, Status> {
+        tracing::info!("stats request");
+
+        let request = request.into_inner();
+
+        let period = match StatsPeriod::try_from(request.period) {
+            Ok(StatsPeriod::Day) => Period::Day,
+            Ok(StatsPeriod::Week) => Period::Week,
+            Err(_) => {
+                return Err(Error::invalid_argument(
+                    "period",
+                    format!("{} is not a period", request.period),
+                )
+                .into())
+            }
         };
-        let to_signer = |signer: db::Signer| internal::Signer {
-            name: signer.name,
-            signature: signer.signature,
+
+        let query = SearchQuery {
+            after: None,
+            ..Register::search_query(request.search.unwrap_or_default())?
         };
 
-        let inside = value.inside.map(to_timestamp);
-        let outside = value.outside.map(to_timestamp);
-        let client = value.client.map(to_signer);
-        let pqrs = value.pqrs.map(to_signer);
+        let stats = self.db.stats(query, period).await?;
 
-        Self {
-            inside,
-            outside,
+        Ok(Response::new(StatsResponse {
+            total: stats.states.iter().map(|(_, count)| count).sum(),
+            states: stats
+                .states
+                .into_iter()
+                .map(|(state, count)| StateCount {
+                    state: state as i32,
+                    count,
+                })
+                .collect(),
+            periods: stats
+                .periods
+                .into_iter()
+                .map(|(start, count)| PeriodCount {
+                    start: Some(Timestamp {
+                        seconds: start,
+                        nanos: 0,
+                    }),
+                    count,
+                })
+                .collect(),
+        }))
+    }
+
+    async fn phase_durations(
+        &self,
+        request: Request<PhaseDurationsRequest>,
+    ) -> Result<Response<PhaseDurationsResponse>, Status> {
+        tracing::info!("phase durations request");
+
+        let request = request.into_inner();
+
+        let query = SearchQuery {
+            after: None,
+            ..Register::search_query(request.search.unwrap_or_default())?
+        };
+
+        let mut cursor = self.db.search(query).await?;
+        let mut durations = db::Phase::ALL.map(|_| vec![]);
+
+        while let Some(record) = cursor.next().await {
+            let Some(traces) = record?.traces else {
+                continue;
+            };
+
+            for (phase, durations) in db::Phase::ALL.into_iter().zip(durations.iter_mut()) {
+                durations.extend(traces.duration(phase));
+            }
+        }
+
+        Ok(Response::new(PhaseDurationsResponse {
+            phases: db::Phase::ALL
+                .into_iter()
+                .zip(durations)
+                .map(|(phase, durations)| phase_durations(phase, durations))
+                .collect(),
+        }))
+    }
+}
+
+impl Register {
+    pub(crate) fn new(
+        db: Arc<dyn Storage>,
+        heartbeat: Option<Duration>,
+        overdue: Arc<Overdue>,
+        verify_signatures: bool,
+        pqrs_signer: SignerBinding,
+        chain: Chain,
+    ) -> Self {
+        Self {
+            db,
+            heartbeat,
+            overdue,
+            verify_signatures,
+            pqrs_signer,
+            chain,
+        }
+    }
+
+    fn search_query(request: SearchRequest) -> Result<SearchQuery, Error> {
+        let states = request
+            .states
+            .iter()
+            .map(|i| FromPrimitive::from_i32(i.to_owned()).unwrap_or(db::RecordState::Unspecified))
+            .collect();
+
+        let range = request.range.and_then(Register::time_range);
+
+        let traces = [
+            (TraceField::CollectedInside, request.collected_inside),
+            (TraceField::CollectedOutside, request.collected_outside),
+            (TraceField::ReturnedInside, request.returned_inside),
+            (TraceField::ReturnedOutside, request.returned_outside),
+        ]
+        .into_iter()
+        .filter_map(|(field, range)| Some((field, range.and_then(Register::time_range)?)))
+        .collect();
+
+        let sort = Sort {
+            field: match SortField::try_from(request.sort) {
+                Ok(SortField::Created) => storage::SortField::Created,
+                Ok(SortField::State) => storage::SortField::State,
+                Ok(SortField::Summary) => storage::SortField::Summary,
+                Ok(SortField::Relevance) => storage::SortField::Relevance,
+                Err(_) => {
+                    return Err(Error::invalid_argument(
+                        "sort",
+                        format!("{} is not a sort field", request.sort),
+                    ))
+                }
+            },
+            // most relevant first
+            descending: request.descending || request.sort == SortField::Relevance as i32,
+        };
+
+        let text = request.text.filter(|text| !text.trim().is_empty());
+        if sort.field == storage::SortField::Relevance && text.is_none() {
+            return Err(Error::invalid_argument(
+                "sort",
+                "relevance requires a text search",
+            ));
+        }
+
+        let after = match request.page_token.as_str() {
+            "" => None,
+            token => Some(PageKey::from_token(token, sort)?),
+        };
+
+        Ok(SearchQuery {
+            states,
+            range,
+            text,
+            traces,
+            client_name: request.client_name,
+            pqrs_name: request.pqrs_name,
+            sort,
+            after,
+            limit: None,
+        })
+    }
+
+    /// Range in seconds, until now if no end. None if no begin
+    fn time_range(range: TimestampRange) -> Option<(i64, i64)> {
+        let mut rangefilter = (0, 0);
+
+        if let Some(begin) = range.begin {
+            rangefilter.0 = begin.seconds;
+            rangefilter.1 = match range.end {
+                Some(end) => end.seconds,
+                None => Utc::now().timestamp(),
+            };
+
+            Some(rangefilter)
+        } else {
+            None
+        }
+    }
+
+    /// Wait for the next heartbeat, forever if disabled
+    async fn tick(heartbeat: &mut Option<Interval>) {
+        match heartbeat {
+            Some(heartbeat) => {
+                heartbeat.tick().await;
+            }
+            None => future::pending().await,
+        }
+    }
+
+    /// Wait for the next overdue record, forever if not watched
+    async fn next_overdue(overdue: &mut Option<broadcast::Receiver<db::Record>>) -> db::Record {
+        loop {
+            let Some(receiver) = overdue.as_mut() else {
+                return future::pending().await;
+            };
+
+            match receiver.recv().await {
+                Ok(record) => return record,
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    tracing::warn!("watch missed {} overdue records", missed);
+                }
+                Err(broadcast::error::RecvError::Closed) => *overdue = None,
+            }
+        }
+    }
+
+    fn now() -> Timestamp {
+        Timestamp {
+            seconds: Utc::now().timestamp(),
+            nanos: 0,
+        }
+    }
+
+    /// Caller of a request, anonymous if unknown
+    fn caller<T>(request: &Request<T>) -> Caller {
+        request
+            .extensions()
+            .get::<Caller>()
+            .cloned()
+            .unwrap_or_else(Caller::anonymous)
+    }
+
+    /// Origin of an update requested by an RPC
+    fn origin<T>(request: &Request<T>, rpc: &'static str) -> Origin {
+        Origin {
+            caller: Register::caller(request).id,
+            rpc,
+        }
+    }
+
+    /// Audited call of a mutating RPC
+    fn call<T: prost::Message>(request: &Request<T>, rpc: &'static str, record_id: &str) -> Call {
+        Call::new(Register::origin(request, rpc), request.get_ref(), record_id)
+    }
+
+    /// Append the outcome of a call to the audit trail
+    ///
+    /// The outcome is returned as is: the call is done even if the audit trail can't be written.
+    async fn audited<T>(
+        &self,
+        call: Call,
+        result: Result<Response<T>, Status>,
+    ) -> Result<Response<T>, Status> {
+        let rpc = call.origin.rpc;
+
+        if let Err(e) = self.db.append_audit(call.entry(&result)).await {
+            tracing::error!("audit of {} not written: {}", rpc, e);
+        }
+
+        result
+    }
+
+    /// Send the content of a stored file to the client, by chunks
+    fn send_chunks<T, F>(
+        rpc: &'static str,
+        mut data: ByteStream,
+        mut message: F,
+    ) -> ReceiverStream<Result<T, Status>>
+    where
+        T: Send + 'static,
+        F: FnMut(Vec<u8>) -> T + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel::<Result<T, Status>>(4);
+
+        tokio::spawn(async move {
+            while let Some(chunk) = data.next().await {
+                let chunk = chunk.map(&mut message).map_err(Status::from);
+
+                if tx.send(chunk).await.is_err() {
+                    tracing::info!("{} closed by client", rpc);
+                    return;
+                }
+            }
+
+            tracing::debug!("{} closed", rpc);
+        });
+
+        ReceiverStream::new(rx)
+    }
+
+    fn empty_response() -> Response<()> {
+        Response::new(())
+    }
+
+    async fn client_time_trace(
+        &self,
+        request: Request<TimestampTrace>,
+        target: db::TimeTraceFor,
+        rpc: &'static str,
+    ) -> Result<Response<()>, Status> {
+        let call = Register::call(&request, rpc, &request.get_ref().id);
+        let request = request.into_inner();
+
+        tracing::info!("trace request for {}", request.id);
+
+        let time = request.time.map_or(0, |time| time.seconds);
+
+        let result = self
+            .db
+            .client_time_trace(db::StringId(request.id), time, target, call.origin.clone())
+            .await
+            .map(|_| Register::empty_response())
+            .map_err(Status::from);
+
+        self.audited(call, result).await
+    }
+
+    async fn signature_trace(
+        &self,
+        request: Request<SignerTrace>,
+        target: db::SignatureTraceFor,
+        rpc: &'static str,
+    ) -> Result<Response<()>, Status> {
+        let call = Register::call(&request, rpc, &request.get_ref().id);
+        let caller = Register::caller(&request);
+        let request = request.into_inner();
+
+        tracing::info!("sign request for {}", request.id);
+
+        let result = match request.signer {
+            None => Err(Error::invalid_argument("signer", "signer is required")),
+            Some(signer) => {
+                let mut signer = db::Signer {
+                    name: signer.name,
+                    signature: signer.signature,
+                    signed: signer.time.map(|time| time.seconds),
+                    image: None,
+                    identity: None,
+                };
+                let id = db::StringId(request.id);
+
+                let checked = match self.bind_signer(&caller, &mut signer, target) {
+                    Ok(()) => self.check_signature(&id, &signer, target).await,
+                    Err(e) => Err(e),
+                };
+
+                match checked {
+                    Ok(()) => {
+                        self.signed(id, signer, request.image, target, call.origin.clone())
+                            .await
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+        }
+        .map(|_| Register::empty_response())
+        .map_err(Status::from);
+
+        self.audited(call, result).await
+    }
+
+    /// Trace a checked signature, with its image if any
+    async fn signed(
+        &self,
+        id: db::StringId,
+        mut signer: db::Signer,
+        image: Option<SignatureImage>,
+        target: db::SignatureTraceFor,
+        origin: Origin,
+    ) -> Result<(), Error> {
+        let Some(image) = image else {
+            return self.db.signature_trace(id, signer, target, origin).await;
+        };
+
+        signature::check_image(&image.content_type, &image.data)?;
+
+        let image = self.db.insert_image(image.content_type, image.data).await?;
+        signer.image = Some(image);
+
+        let result = self.db.signature_trace(id, signer, target, origin).await;
+
+        if result.is_err() {
+            // the image is not referenced by the record
+            if let Err(e) = self.db.delete_image(image).await {
+                tracing::warn!("signature image {} not deleted: {}", image, e);
+            }
+        }
+
+        result
+    }
+
+    /// Bind a PQRS signature to the authenticated caller
+    ///
+    /// The identity of the caller is recorded, the declared name is kept for display.
+    fn bind_signer(
+        &self,
+        caller: &Caller,
+        signer: &mut db::Signer,
+        target: db::SignatureTraceFor,
+    ) -> Result<(), Error> {
+        if !target.by_pqrs() || caller.is_anonymous() {
+            return Ok(());
+        }
+
+        signer.identity = Some(caller.id.clone());
+
+        if caller.name.as_deref() == Some(signer.name.as_str()) {
+            return Ok(());
+        }
+
+        match self.pqrs_signer {
+            SignerBinding::Lenient => {
+                tracing::warn!("{} signs as {}", caller.id, signer.name);
+                Ok(())
+            }
+            SignerBinding::Strict => Err(Error::SignerNotCaller {
+                signer: signer.name.clone(),
+                caller: caller.id.clone(),
+            }),
+        }
+    }
+
+    /// Check the signature of the state reached by the trace, if signatures are verified
+    async fn check_signature(
+        &self,
+        id: &db::StringId,
+        signer: &db::Signer,
+        target: db::SignatureTraceFor,
+    ) -> Result<(), Error> {
+        if !self.verify_signatures {
+            return Ok(());
+        }
+
+        let time = signer
+            .signed
+            .ok_or_else(|| Error::invalid_argument("signer.time", "signing time is required"))?;
+        let key =
+            self.db
+                .signer_key(&signer.name)
+                .await?
+                .ok_or_else(|| Error::SignerKeyNotFound {
+                    signer: signer.name.clone(),
+                })?;
+
+        // the canonical form of the id, whatever its case in the request
+        let record_id = id.to_object_id()?.to_string();
+        let state = workflow::step(target.into()).to;
+        let digest = signature::digest(&record_id, state_name(state), time);
+
+        signature::verify(&key, &signer.signature, &digest)
+    }
+}
+
+impl From<Error> for Status {
+    fn from(value: Error) -> Self {
+        let message = value.to_string();
+
+        match value {
+            Error::InvalidArgument { field, description } => Status::with_error_details(
+                Code::InvalidArgument,
+                message,
+                ErrorDetails::with_bad_request_violation(field, description),
+            ),
+            Error::NotFound { id } => Status::with_error_details(
+                Code::NotFound,
+                message,
+                ErrorDetails::with_resource_info(RECORD_RESOURCE, id, "", "record not found"),
+            ),
+            Error::AttachmentNotFound { id } => Status::with_error_details(
+                Code::NotFound,
+                message,
+                ErrorDetails::with_resource_info(
+                    ATTACHMENT_RESOURCE,
+                    id,
+                    "",
+                    "attachment not found",
+                ),
+            ),
+            Error::ImageNotFound { id } => Status::with_error_details(
+                Code::NotFound,
+                message,
+                ErrorDetails::with_resource_info(
+                    SIGNATURE_IMAGE_RESOURCE,
+                    id,
+                    "",
+                    "signature image not found",
+                ),
+            ),
+            Error::FailedPrecondition {
+                id,
+                current,
+                expected,
+            } => {
+                let expected = expected
+                    .into_iter()
+                    .map(state_name)
+                    .collect::<Vec<&str>>()
+                    .join(",");
+
+                let mut details = ErrorDetails::with_precondition_failure_violation(
+                    "STATE",
+                    id,
+                    format!("record state must be one of {}", expected),
+                );
+                details.set_error_info(
+                    "INVALID_STATE",
+                    ERROR_DOMAIN,
+                    HashMap::from([
+                        ("current".to_owned(), state_name(current).to_owned()),
+                        ("expected".to_owned(), expected),
+                    ]),
+                );
+
+                Status::with_error_details(Code::FailedPrecondition, message, details)
+            }
+            Error::SignerKeyNotFound { signer } => {
+                let mut details = ErrorDetails::with_precondition_failure_violation(
+                    "SIGNER_KEY",
+                    signer.clone(),
+                    "register the public key of the signer with RegisterSignerKey",
+                );
+                details.set_error_info(
+                    "SIGNER_KEY_NOT_FOUND",
+                    ERROR_DOMAIN,
+                    HashMap::from([("signer".to_owned(), signer)]),
+                );
+
+                Status::with_error_details(Code::FailedPrecondition, message, details)
+            }
+            Error::SignatureMismatch { signer } => {
+                let mut details = ErrorDetails::with_bad_request_violation(
+                    "signer.signature",
+                    "the signature doesn't match the signed digest and the signer key",
+                );
+                details.set_error_info(
+                    "SIGNATURE_MISMATCH",
+                    ERROR_DOMAIN,
+                    HashMap::from([("signer".to_owned(), signer)]),
+                );
+
+                Status::with_error_details(Code::InvalidArgument, message, details)
+            }
+            Error::SignerNotCaller { signer, caller } => {
+                let mut details = ErrorDetails::new();
+                details.set_error_info(
+                    "SIGNER_NOT_CALLER",
+                    ERROR_DOMAIN,
+                    HashMap::from([("signer".to_owned(), signer), ("caller".to_owned(), caller)]),
+                );
+
+                Status::with_error_details(Code::PermissionDenied, message, details)
+            }
+            Error::ResumePointExpired(point) => {
+                let mut details = ErrorDetails::with_precondition_failure_violation(
+                    "RESUME_POINT",
+                    point,
+                    "changes are not kept anymore, watch again without resume_token or start_at",
+                );
+                details.set_error_info("RESUME_POINT_EXPIRED", ERROR_DOMAIN, HashMap::new());
+
+                Status::with_error_details(Code::FailedPrecondition, message, details)
+            }
+            Error::Unavailable(_) => Status::with_error_details(
+                Code::Unavailable,
+                message,
+                ErrorDetails::with_retry_info(Some(RETRY_DELAY)),
+            ),
+            Error::Internal(_) => Status::internal(message),
+        }
+    }
+}
+
+impl From<crate::mongodb::Record> for internal::Record {
+    fn from(value: crate::mongodb::Record) -> Self {
+        let created = value.created.map(|created| Timestamp {
+            seconds: created,
+            nanos: 0,
+        });
+        let duration = |phase| {
+            value
+                .traces
+                .as_ref()
+                .and_then(|traces| traces.duration(phase))
+                .map(to_duration)
+        };
+        let collect_duration = duration(db::Phase::Collect);
+        let out_duration = duration(db::Phase::Out);
+        let return_duration = duration(db::Phase::Return);
+
+        let traces = value.traces.map(|traces| Traces {
+            collected: traces.collected.map(|trace| trace.into()),
+            returned: traces.returned.map(|trace| trace.into()),
+        });
+
+        Self {
+            id: value.id.unwrap().to_string(),
+            api_version: value.api_version,
+            created,
+            summary: value.summary,
+            traces,
+            state: value.state as i32,
+            score: value.score,
+            collect_duration,
+            out_duration,
+            return_duration,
+            transitioned: value.transitioned.map(|transitioned| Timestamp {
+                seconds: transitioned,
+                nanos: 0,
+            }),
+            history: value
+                .history
+                .into_iter()
+                .map(|entry| entry.into())
+                .collect(),
+            seal: value.seal,
+            attachments: value
+                .attachments
+                .into_iter()
+                .map(|attachment| attachment.into())
+                .collect(),
+        }
+    }
+}
+
+impl From<db::Attachment> for Attachment {
+    fn from(value: db::Attachment) -> Self {
+        Self {
+            id: value.id.to_hex(),
+            filename: value.filename,
+            content_type: value.content_type,
+            length: value.length as u64,
+            sha256: value.sha256,
+            uploaded: Some(Timestamp {
+                seconds: value.uploaded,
+                nanos: 0,
+            }),
+        }
+    }
+}
+
+impl From<db::AuditEntry> for AuditEntry {
+    fn from(value: db::AuditEntry) -> Self {
+        Self {
+            id: value.id.map(|id| id.to_string()).unwrap_or_default(),
+            time: Some(Timestamp {
+                seconds: value.time,
+                nanos: 0,
+            }),
+            caller: value.caller,
+            rpc: value.rpc,
+            record_id: value.record_id,
+            code: value.code,
+            message: value.message,
+            digest: value.digest,
+        }
+    }
+}
+
+impl From<db::Transition> for internal::Transition {
+    fn from(value: db::Transition) -> Self {
+        Self {
+            from: value.from.map_or(0, |state| state as i32),
+            to: value.to as i32,
+            time: Some(Timestamp {
+                seconds: value.time,
+                nanos: 0,
+            }),
+            caller: value.caller,
+            rpc: value.rpc,
+            hash: value.hash.unwrap_or_default(),
+        }
+    }
+}
+
+impl From<crate::mongodb::Trace> for internal::Trace {
+    fn from(value: crate::mongodb::Trace) -> Self {
+        let to_timestamp = |time: i64| Timestamp {
+            seconds: time,
+            nanos: 0,
+        };
+        let to_signer = |signer: db::Signer| internal::Signer {
+            name: signer.name,
+            signature: signer.signature,
+            time: signer.signed.map(to_timestamp),
+            image_id: signer.image.map(|id| id.to_hex()),
+            identity: signer.identity,
+        };
+
+        let inside = value.inside.map(to_timestamp);
+        let outside = value.outside.map(to_timestamp);
+        let client = value.client.map(to_signer);
+        let pqrs = value.pqrs.map(to_signer);
+
+        Self {
+            inside,
+            outside,
             client,
             pqrs,
         }
     }
 }
+
+fn to_duration(seconds: i64) -> prost_types::Duration {
+    prost_types::Duration { seconds, nanos: 0 }
+}
+
+/// Average, median and 95th percentile of durations
+fn phase_durations(phase: db::Phase, mut durations: Vec<i64>) -> PhaseDurations {
+    let phase = match phase {
+        db::Phase::Collect => Phase::Collect,
+        db::Phase::Out => Phase::Out,
+        db::Phase::Return => Phase::Return,
+    } as i32;
+    let count = durations.len();
+
+    if count == 0 {
+        return PhaseDurations {
+            phase,
+            ..Default::default()
+        };
+    }
+
+    durations.sort_unstable();
+
+    let average = durations.iter().sum::<i64>() / count as i64;
+    let median = match count % 2 {
+        0 => (durations[count / 2 - 1] + durations[count / 2]) / 2,
+        _ => durations[count / 2],
+    };
+    // nearest rank
+    let p95 = durations[(count * 95).div_ceil(100) - 1];
+
+    PhaseDurations {
+        phase,
+        count: count as u64,
+        average: Some(to_duration(average)),
+        median: Some(to_duration(median)),
+        p95: Some(to_duration(p95)),
+    }
+}
+
+/// Proto name of a record state (eg: `COLLECT_PQRS_SIGNATURE`)
+fn state_name(state: db::RecordState) -> &'static str {
+    internal::RecordState::try_from(state as i32)
+        .unwrap_or_default()
+        .as_str_name()
+}
+
+/// Record state from its proto name (eg: `COLLECT_PQRS_SIGNATURE`), in any case
+pub(crate) fn state_from_name(name: &str) -> Result<db::RecordState, Error> {
+    internal::RecordState::from_str_name(&name.to_ascii_uppercase())
+        .and_then(|state| FromPrimitive::from_i32(state as i32))
+        .ok_or_else(|| Error::invalid_argument("state", format!("unknown state {}", name)))
+}
+
+/// Records of a snapshot as added events, ended by a marker
+fn snapshot_events(records: Vec<db::Record>, resume_token: String) -> Vec<RecordEvent> {
+    let server_time = Register::now();
+
+    records
+        .into_iter()
+        .map(|record| (EventType::Added, Some(record.into())))
+        .chain([(EventType::SnapshotEnd, None)])
+        .map(|(event_type, record)| RecordEvent {
+            event_type: event_type as i32,
+            record,
+            resume_token: resume_token.clone(),
+            server_time: Some(server_time.clone()),
+        })
+        .collect()
+}
+
+/// Convert a storage change to a watch event
+// the status ends the watch stream
+#[allow(clippy::result_large_err)]
+fn to_record_event(event: ChangeEvent) -> Result<RecordEvent, Status> {
+    let (event_type, record) = match event.change {
+        Change::Invalidated => {
+            return Err(Status::cancelled(
+                "A global issue with the collection occurs on the database side",
+            ))
+        }
+        Change::Added(record) => (EventType::Added, record.into()),
+        Change::Modified(record) => (EventType::Modified, record.into()),
+        Change::Deleted(id) => (
+            EventType::Deleted,
+            Record {
+                id: id.to_string(),
+                ..Default::default()
+            },
+        ),
+    };
+
+    Ok(RecordEvent {
+        event_type: event_type as i32,
+        record: Some(record),
+        resume_token: event.resume_token,
+        server_time: Some(Register::now()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use internal::{register_server::Register as _, RecordState};
+
+    use super::*;
+    use crate::storage::Memory;
+
+    fn register() -> Register {
+        register_with(false, SignerBinding::Lenient)
+    }
+
+    fn register_with(verify_signatures: bool, pqrs_signer: SignerBinding) -> Register {
+        let chain = Chain::new(b"a test key of at least 32 bytes!");
+        let db: Arc<dyn Storage> = Arc::new(Memory::new(chain.clone()));
+        let overdue = Arc::new(Overdue::new(db.clone(), vec![], Duration::from_secs(60)));
+
+        Register::new(db, None, overdue, verify_signatures, pqrs_signer, chain)
+    }
+
+    async fn new_draft(register: &Register, summary: &str) -> String {
+        let draft = Draft {
+            id: String::new(),
+            summary: summary.to_owned(),
+        };
+
+        register
+            .new_draft(Request::new(draft))
+            .await
+            .expect("draft created")
+            .into_inner()
+            .id
+    }
+
+    async fn record(register: &Register, id: &str) -> Result<Record, Status> {
+        let request = Request::new(RecordId { id: id.to_owned() });
+
+        register
+            .search_by_id(request)
+            .await
+            .map(Response::into_inner)
+    }
+
+    #[tokio::test]
+    async fn draft_is_updated_until_submitted() {
+        let register = register();
+        let id = new_draft(&register, "first").await;
+
+        let update = Draft {
+            id: id.clone(),
+            summary: "second".to_owned(),
+        };
+        register
+            .update_draft(Request::new(update.clone()))
+            .await
+            .expect("draft updated");
+
+        let draft = record(&register, &id).await.expect("draft found");
+        assert_eq!(draft.summary, "second");
+        assert_eq!(draft.state(), RecordState::Draft);
+
+        register
+            .submit_draft(Request::new(RecordId { id: id.clone() }))
+            .await
+            .expect("draft submitted");
+
+        let status = register
+            .update_draft(Request::new(update))
+            .await
+            .expect_err("submitted record updated");
+        assert_eq!(status.code(), Code::FailedPrecondition);
+
+        let created = record(&register, &id).await.expect("record found");
+        assert_eq!(created.summary, "second");
+        assert_eq!(created.state(), RecordState::Created);
+    }
+
+    #[tokio::test]
+    async fn deleted_draft_is_not_found() {
+        let register = register();
+        let id = new_draft(&register, "draft").await;
+
+        register
+            .delete_draft(Request::new(RecordId { id: id.clone() }))
+            .await
+            .expect("draft deleted");
+
+        let status = record(&register, &id)
+            .await
+            .expect_err("deleted draft found");
+        assert_eq!(status.code(), Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn get_records_lists_missing_and_malformed_ids() {
+        let register = register();
+        let found = new_draft(&register, "found").await;
+        let missing = ::mongodb::bson::oid::ObjectId::new().to_hex();
+
+        let ids = [found.as_str(), "not-an-id", missing.as_str()]
+            .into_iter()
+            .map(|id| RecordId { id: id.to_owned() })
+            .collect();
+
+        let response = register
+            .get_records(Request::new(GetRecordsRequest { ids }))
+            .await
+            .expect("records got")
+            .into_inner();
+
+        let found_ids: Vec<_> = response.records.iter().map(|record| &record.id).collect();
+        assert_eq!(found_ids, [&found]);
+        assert_eq!(response.missing_ids, [missing]);
+        assert_eq!(response.invalid_ids, ["not-an-id"]);
+    }
+
+    #[tokio::test]
+    async fn attachments_are_chained() {
+        let register = register();
+        let id = new_draft(&register, "draft").await;
+
+        let origin = Origin {
+            caller: "test".to_owned(),
+            rpc: "UploadAttachment",
+        };
+        let content = attachment::content(tokio_stream::once(Ok(b"content".to_vec())));
+        let attachment = register
+            .db
+            .insert_attachment(
+                db::StringId(id.clone()),
+                "scan.pdf".to_owned(),
+                "application/pdf".to_owned(),
+                content,
+                origin,
+            )
+            .await
+            .expect("attachment uploaded");
+
+        let request = AttachmentId {
+            record_id: id.clone(),
+            id: attachment.id.to_hex(),
+        };
+        register
+            .delete_attachment(Request::new(request))
+            .await
+            .expect("attachment deleted");
+
+        let draft = register
+            .db
+            .search_by_id(db::StringId(id.clone()))
+            .await
+            .expect("draft searched")
+            .expect("draft found");
+        let rpcs: Vec<_> = draft.history.iter().map(|entry| &entry.rpc).collect();
+        assert_eq!(rpcs, ["NewDraft", "UploadAttachment", "DeleteAttachment"]);
+        assert!(draft.attachments.is_empty());
+
+        let verified = register
+            .verify_record(Request::new(RecordId { id }))
+            .await
+            .expect("record verified")
+            .into_inner();
+        assert_eq!(verified.integrity(), Integrity::Intact);
+    }
+
+    /// Submitted record the client entered, waiting for its signature
+    async fn client_inside(register: &Register) -> String {
+        let id = new_draft(register, "record").await;
+        register
+            .submit_draft(Request::new(RecordId { id: id.clone() }))
+            .await
+            .expect("draft submitted");
+
+        let trace = TimestampTrace {
+            id: id.clone(),
+            time: Some(Timestamp {
+                seconds: SIGNED,
+                nanos: 0,
+            }),
+        };
+        register
+            .collect_client_inside(Request::new(trace))
+            .await
+            .expect("client inside");
+
+        id
+    }
+
+    const SIGNED: i64 = 1_717_000_000;
+
+    fn signing_key(seed: u8) -> ed25519_dalek::SigningKey {
+        ed25519_dalek::SigningKey::from_bytes(&[seed; 32])
+    }
+
+    async fn register_key(register: &Register, signer_name: &str, seed: u8) {
+        let key = SignerKey {
+            signer_name: signer_name.to_owned(),
+            public_key: signing_key(seed).verifying_key().to_bytes().to_vec(),
+        };
+
+        register
+            .register_signer_key(Request::new(key))
+            .await
+            .expect("key registered");
+    }
+
+    /// Base64 signature of the digest of a state reached at a time
+    fn sign(seed: u8, id: &str, state: &str, time: i64) -> String {
+        use base64::{engine::general_purpose::STANDARD, Engine};
+        use ed25519_dalek::Signer as _;
+
+        let digest = signature::digest(id, state, time);
+
+        STANDARD.encode(signing_key(seed).sign(&digest).to_bytes())
+    }
+
+    fn signer_trace(id: &str, name: &str, signature: String, time: Option<i64>) -> SignerTrace {
+        SignerTrace {
+            id: id.to_owned(),
+            signer: Some(internal::Signer {
+                name: name.to_owned(),
+                signature,
+                time: time.map(|seconds| Timestamp { seconds, nanos: 0 }),
+                image_id: None,
+                identity: None,
+            }),
+            image: None,
+        }
+    }
+
+    /// Reason of the error info of a status
+    fn reason(status: &Status) -> Option<String> {
+        status
+            .get_error_details()
+            .error_info()
+            .map(|info| info.reason.clone())
+    }
+
+    #[tokio::test]
+    async fn signature_of_the_digest_is_accepted() {
+        let register = register_with(true, SignerBinding::Lenient);
+        register_key(&register, "client", 1).await;
+        let id = client_inside(&register).await;
+
+        let signature = sign(1, &id, "COLLECT_CLIENT_SIGNATURE", SIGNED);
+        register
+            .collect_client_signature(Request::new(signer_trace(
+                &id,
+                "client",
+                signature.clone(),
+                Some(SIGNED),
+            )))
+            .await
+            .expect("signature accepted");
+
+        let record = record(&register, &id).await.expect("record found");
+        assert_eq!(record.state(), RecordState::CollectClientSignature);
+        let signer = record
+            .traces
+            .and_then(|traces| traces.collected)
+            .and_then(|trace| trace.client)
+            .expect("client signer");
+        assert_eq!(signer.signature, signature);
+    }
+
+    #[tokio::test]
+    async fn tampered_or_foreign_signature_is_mismatched() {
+        let register = register_with(true, SignerBinding::Lenient);
+        register_key(&register, "client", 1).await;
+        let id = client_inside(&register).await;
+
+        let signatures = [
+            // another time
+            sign(1, &id, "COLLECT_CLIENT_SIGNATURE", SIGNED + 1),
+            // another state
+            sign(1, &id, "RETURN_CLIENT_SIGNATURE", SIGNED),
+            // another key
+            sign(2, &id, "COLLECT_CLIENT_SIGNATURE", SIGNED),
+        ];
+
+        for signature in signatures {
+            let status = register
+                .collect_client_signature(Request::new(signer_trace(
+                    &id,
+                    "client",
+                    signature,
+                    Some(SIGNED),
+                )))
+                .await
+                .expect_err("signature accepted");
+
+            assert_eq!(status.code(), Code::InvalidArgument);
+            assert_eq!(reason(&status).as_deref(), Some("SIGNATURE_MISMATCH"));
+        }
+
+        let record = record(&register, &id).await.expect("record found");
+        assert_eq!(record.state(), RecordState::CollectClientInside);
+    }
+
+    #[tokio::test]
+    async fn non_base64_signature_is_invalid() {
+        let register = register_with(true, SignerBinding::Lenient);
+        register_key(&register, "client", 1).await;
+        let id = client_inside(&register).await;
+
+        let status = register
+            .collect_client_signature(Request::new(signer_trace(
+                &id,
+                "client",
+                "not base64!".to_owned(),
+                Some(SIGNED),
+            )))
+            .await
+            .expect_err("signature accepted");
+
+        assert_eq!(status.code(), Code::InvalidArgument);
+        let violations = status
+            .get_details_bad_request()
+            .expect("bad request")
+            .field_violations;
+        assert_eq!(violations[0].field, "signer.signature");
+    }
+
+    #[tokio::test]
+    async fn signer_without_key_is_not_found() {
+        let register = register_with(true, SignerBinding::Lenient);
+        register_key(&register, "client", 1).await;
+        let id = client_inside(&register).await;
+
+        let signature = sign(1, &id, "COLLECT_CLIENT_SIGNATURE", SIGNED);
+        let status = register
+            .collect_client_signature(Request::new(signer_trace(
+                &id,
+                "unknown",
+                signature,
+                Some(SIGNED),
+            )))
+            .await
+            .expect_err("signature accepted");
+
+        assert_eq!(status.code(), Code::FailedPrecondition);
+        assert_eq!(reason(&status).as_deref(), Some("SIGNER_KEY_NOT_FOUND"));
+    }
+
+    #[tokio::test]
+    async fn signature_without_time_is_invalid() {
+        let register = register_with(true, SignerBinding::Lenient);
+        register_key(&register, "client", 1).await;
+        let id = client_inside(&register).await;
+
+        let signature = sign(1, &id, "COLLECT_CLIENT_SIGNATURE", SIGNED);
+        let status = register
+            .collect_client_signature(Request::new(signer_trace(&id, "client", signature, None)))
+            .await
+            .expect_err("signature accepted");
+
+        assert_eq!(status.code(), Code::InvalidArgument);
+        let violations = status
+            .get_details_bad_request()
+            .expect("bad request")
+            .field_violations;
+        assert_eq!(violations[0].field, "signer.time");
+    }
+
+    /// Request of an authenticated caller
+    fn as_caller<T>(message: T, name: &str) -> Request<T> {
+        let mut request = Request::new(message);
+        request.extensions_mut().insert(Caller {
+            id: "jwt:agent".to_owned(),
+            name: Some(name.to_owned()),
+        });
+
+        request
+    }
+
+    /// Record the client left, waiting for the PQRS signature
+    async fn client_outside(register: &Register) -> String {
+        let id = client_inside(register).await;
+
+        let signature = sign(1, &id, "COLLECT_CLIENT_SIGNATURE", SIGNED);
+        register
+            .collect_client_signature(Request::new(signer_trace(
+                &id,
+                "client",
+                signature,
+                Some(SIGNED),
+            )))
+            .await
+            .expect("client signature");
+
+        let trace = TimestampTrace {
+            id: id.clone(),
+            time: Some(Timestamp {
+                seconds: SIGNED,
+                nanos: 0,
+            }),
+        };
+        register
+            .collect_client_outside(Request::new(trace))
+            .await
+            .expect("client outside");
+
+        id
+    }
+
+    async fn pqrs_signer(register: &Register, id: &str) -> Option<internal::Signer> {
+        record(register, id)
+            .await
+            .expect("record found")
+            .traces
+            .and_then(|traces| traces.collected)
+            .and_then(|trace| trace.pqrs)
+    }
+
+    #[tokio::test]
+    async fn strict_signer_mismatch_is_denied() {
+        let register = register_with(false, SignerBinding::Strict);
+        let id = client_outside(&register).await;
+
+        let trace = signer_trace(&id, "Bob", "signature".to_owned(), Some(SIGNED));
+        let status = register
+            .collect_pqrs_signature(as_caller(trace, "Alice"))
+            .await
+            .expect_err("signature accepted");
+
+        assert_eq!(status.code(), Code::PermissionDenied);
+        assert_eq!(reason(&status).as_deref(), Some("SIGNER_NOT_CALLER"));
+        assert!(pqrs_signer(&register, &id).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn matching_signer_is_bound_to_the_caller() {
+        let register = register_with(false, SignerBinding::Strict);
+        let id = client_outside(&register).await;
+
+        let trace = signer_trace(&id, "Alice", "signature".to_owned(), Some(SIGNED));
+        register
+            .collect_pqrs_signature(as_caller(trace, "Alice"))
+            .await
+            .expect("signature accepted");
+
+        let signer = pqrs_signer(&register, &id).await.expect("pqrs signer");
+        assert_eq!(signer.name, "Alice");
+        assert_eq!(signer.identity.as_deref(), Some("jwt:agent"));
+
+        let record = register
+            .db
+            .search_by_id(db::StringId(id))
+            .await
+            .expect("record searched")
+            .expect("record found");
+        let entry = record.history.last().expect("history entry");
+        assert_eq!(entry.caller, "jwt:agent");
+        let identity = entry
+            .set
+            .get_document("traces.collected.pqrs")
+            .and_then(|signer| signer.get_str("identity"));
+        assert_eq!(identity, Ok("jwt:agent"));
+    }
+
+    #[tokio::test]
+    async fn lenient_signer_mismatch_is_accepted() {
+        let register = register_with(false, SignerBinding::Lenient);
+        let id = client_outside(&register).await;
+
+        let trace = signer_trace(&id, "Bob", "signature".to_owned(), Some(SIGNED));
+        register
+            .collect_pqrs_signature(as_caller(trace, "Alice"))
+            .await
+            .expect("signature accepted");
+
+        let signer = pqrs_signer(&register, &id).await.expect("pqrs signer");
+        assert_eq!(signer.name, "Bob");
+        assert_eq!(signer.identity.as_deref(), Some("jwt:agent"));
+    }
+
+    #[tokio::test]
+    async fn client_signature_is_not_bound() {
+        let register = register_with(false, SignerBinding::Strict);
+        let id = client_inside(&register).await;
+
+        let trace = signer_trace(&id, "client", "signature".to_owned(), Some(SIGNED));
+        register
+            .collect_client_signature(as_caller(trace, "Alice"))
+            .await
+            .expect("signature accepted");
+
+        let signer = record(&register, &id)
+            .await
+            .expect("record found")
+            .traces
+            .and_then(|traces| traces.collected)
+            .and_then(|trace| trace.client)
+            .expect("client signer");
+        assert_eq!(signer.name, "client");
+        assert_eq!(signer.identity, None);
+    }
+
+    #[tokio::test]
+    async fn failed_call_is_audited() {
+        let register = register();
+        let id = new_draft(&register, "draft").await;
+
+        let request = || Request::new(RecordId { id: id.clone() });
+        register
+            .submit_draft(request())
+            .await
+            .expect("draft submitted");
+        let status = register
+            .submit_draft(request())
+            .await
+            .expect_err("record submitted twice");
+        assert_eq!(status.code(), Code::FailedPrecondition);
+
+        let query = AuditQuery {
+            range: None,
+            record_id: Some(id.clone()),
+        };
+        let audited: Vec<_> = register
+            .db
+            .audit(query)
+            .await
+            .expect("audit read")
+            .map(|entry| entry.expect("audit entry"))
+            .filter(|entry| entry.rpc == "SubmitDraft")
+            .map(|entry| (entry.code, entry.caller))
+            .collect()
+            .await;
+
+        assert_eq!(
+            audited,
+            [
+                (Code::Ok as i32, "anonymous".to_owned()),
+                (Code::FailedPrecondition as i32, "anonymous".to_owned()),
+            ]
+        );
+    }
+
+    /// Count, average, median and p95 in seconds
+    fn statistics(durations: Vec<i64>) -> (u64, Option<i64>, Option<i64>, Option<i64>) {
+        let phase = phase_durations(db::Phase::Out, durations);
+        assert_eq!(phase.phase(), Phase::Out);
+        let seconds = |duration: Option<prost_types::Duration>| duration.map(|d| d.seconds);
+
+        (
+            phase.count,
+            seconds(phase.average),
+            seconds(phase.median),
+            seconds(phase.p95),
+        )
+    }
+
+    #[test]
+    fn phase_without_duration_has_no_statistics() {
+        assert_eq!(statistics(vec![]), (0, None, None, None));
+    }
+
+    #[test]
+    fn single_duration_is_every_statistic() {
+        assert_eq!(statistics(vec![7]), (1, Some(7), Some(7), Some(7)));
+    }
+
+    #[test]
+    fn median_of_an_even_count_is_the_middle_average() {
+        assert_eq!(statistics(vec![4, 1, 3, 2]), (4, Some(2), Some(2), Some(4)));
+    }
+
+    #[test]
+    fn p95_is_the_nearest_rank() {
+        // rank ceil(0.95 * 21) = 20
+        let durations = (1..=21).rev().collect();
+
+        assert_eq!(statistics(durations), (21, Some(11), Some(11), Some(20)));
+    }
+
+    #[tokio::test]
+    async fn malformed_id_is_invalid() {
+        let register = register();
+
+        let status = record(&register, "not-an-id")
+            .await
+            .expect_err("malformed id found");
+        assert_eq!(status.code(), Code::InvalidArgument);
+    }
+}
@@ -4,53 +4,102 @@
 //!
 //! Support:
 //! - Cors
-//! - Auth (POC)
-//! - TLS
+//! - Auth (POC tokens or JWT) with roles per RPC
+//! - TLS, with optional client certificates (mutual TLS), reloaded without restart
 //! - Grpc-web
 
 use std::{error::Error, sync::Arc, time::Duration};
 
 use http::{HeaderName, Method};
-use tokio::signal;
-use tonic::transport::{Identity, Server, ServerTlsConfig};
+use tokio::{net::TcpListener, signal};
+use tonic::transport::Server;
 use tonic_web::GrpcWebLayer;
 use tower_http::cors::{Any, CorsLayer};
 
 use crate::{
-    auth::Auth,
+    auth::{Auth, Authorized, Jwt},
+    chain::Chain,
     config::AppConfig,
     observability,
-    register::{Register, RegisterServer},
+    overdue::Overdue,
+    register::{self, Register, RegisterServer},
+    storage,
+    tls::Tls,
 };
 
 pub(crate) async fn run() -> Result<(), Box<dyn Error>> {
     observability::init_tracing();
 
-    let config = AppConfig::build()?;
+    let mut config = AppConfig::build()?;
 
-    let auth = Arc::new(Auth::new(config.service.tokens.unwrap_or(vec![]))?);
-
-    let service =
-        RegisterServer::with_interceptor(Register::new(config.mongodb).await?, move |req| {
-            auth.check_auth(req)
-        });
+    let jwt = match config.service.jwt.take() {
+        Some(jwt) => {
+            let jwt = Arc::new(Jwt::load(jwt).await?);
+            tokio::spawn(jwt.clone().run());
+            Some(jwt)
+        }
+        None => None,
+    };
+    let auth = Arc::new(Auth::new(
+        config.service.tokens.take().unwrap_or(vec![]),
+        config.service.clients.take().unwrap_or(vec![]),
+        jwt,
+    ));
+
+    let chain = Chain::from_config(&config.chain)?;
+    let storage = storage::from_config(&config, chain.clone()).await?;
+
+    let heartbeat = config
+        .service
+        .heartbeat
+        .filter(|seconds| *seconds > 0)
+        .map(Duration::from_secs);
+
+    let thresholds = config
+        .overdue
+        .thresholds
+        .iter()
+        .map(|(state, seconds)| {
+            register::state_from_name(state).map(|state| (state, Duration::from_secs(*seconds)))
+        })
+        .collect::<Result<_, _>>()?;
+    let overdue = Arc::new(Overdue::new(
+        storage.clone(),
+        thresholds,
+        Duration::from_secs(config.overdue.interval.max(1)),
+    ));
+    tokio::spawn(overdue.clone().run());
+
+    let register = Register::new(
+        storage.clone(),
+        heartbeat,
+        overdue,
+        config.service.verify_signatures,
+        config.service.pqrs_signer,
+        chain,
+    );
+
+    let service = Authorized::new(RegisterServer::new(register), auth, storage);
 
     let addr = config.service.listen.parse()?;
 
     tracing::info!("use tls: {}", config.service.tls);
+    if !config.service.tls && config.service.client_ca.is_some() {
+        tracing::warn!("client_ca is ignored without tls");
+    }
     tracing::info!("listening on {}", addr);
 
     if config.service.tls {
-        let cert = tokio::fs::read("config/server.crt").await?;
-        let key = tokio::fs::read("config/server.key").await?;
-        let identity = Identity::from_pem(cert, key);
+        let tls = Arc::new(Tls::load(&config.service).await?);
+        tokio::spawn(tls.clone().run());
+
+        let listener = TcpListener::bind(addr).await?;
 
         Server::builder()
-            .tls_config(ServerTlsConfig::new().identity(identity))?
             .layer(cors_layer())
             .layer(GrpcWebLayer::new())
             .add_service(service)
-            .serve_with_shutdown(addr, shutdown_signal())
+            .serve_with_incoming_shutdown(tls.incoming(listener), shutdown_signal())
             .await?;
     } else {
         Server::builder()
@@ -90,14 +139,19 @@ async fn shutdown_signal() {
 }
 
 const DEFAULT_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
-const DEFAULT_EXPOSED_HEADERS: [&str; 3] =
-    ["grpc-status", "grpc-message", "grpc-status-details-bin"];
-const DEFAULT_ALLOW_HEADERS: [&str; 5] = [
+const DEFAULT_EXPOSED_HEADERS: [&str; 4] = [
+    "grpc-status",
+    "grpc-message",
+    "grpc-status-details-bin",
+    "next-page-token",
+];
+const DEFAULT_ALLOW_HEADERS: [&str; 6] = [
     "x-grpc-web",
     "content-type",
     "x-user-agent",
     "grpc-timeout",
     "apikey",
+    "authorization",
 ];
 
 fn cors_layer() -> CorsLayer {
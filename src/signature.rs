@@ -0,0 +1,92 @@
+//! Signer signatures
+//!
+//! A signer signs with its Ed25519 key the digest of the record id, the state reached by the
+//! signature (proto name) and the signing time (seconds):
+//!
+//! ```text
+//! sha256("<record id>:<state>:<time>")
+//! eg: sha256("665f1c2ab1e0a3c4d5e6f708:COLLECT_CLIENT_SIGNATURE:1717000000")
+//! ```
+//!
+//! The signature is sent base64 encoded (standard alphabet) and verified with the public key
+//! registered for the signer name.
+//!
+//! A handwritten signature image (PNG or SVG) can be captured with the signature.
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ed25519_dalek::{Signature, VerifyingKey, PUBLIC_KEY_LENGTH};
+use sha2::{Digest, Sha256};
+
+use crate::{error::Error, mongodb::SignerKey};
+
+/// Maximum size of a signature image
+const MAX_IMAGE_SIZE: usize = 1024 * 1024;
+const PNG_MAGIC: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// Check a signature image is a PNG or SVG image of its content type, and not too large
+pub(crate) fn check_image(content_type: &str, data: &[u8]) -> Result<(), Error> {
+    if data.is_empty() || data.len() > MAX_IMAGE_SIZE {
+        return Err(Error::invalid_argument(
+            "image.data",
+            format!("an image is 1 to {} bytes", MAX_IMAGE_SIZE),
+        ));
+    }
+
+    let matches = match content_type {
+        "image/png" => data.starts_with(PNG_MAGIC),
+        "image/svg+xml" => std::str::from_utf8(data).is_ok_and(|svg| svg.contains("<svg")),
+        _ => {
+            return Err(Error::invalid_argument(
+                "image.content_type",
+                "only image/png and image/svg+xml are accepted",
+            ))
+        }
+    };
+
+    if matches {
+        Ok(())
+    } else {
+        Err(Error::invalid_argument(
+            "image.data",
+            format!("not an {} image", content_type),
+        ))
+    }
+}
+
+/// Digest signed by a signer
+pub(crate) fn digest(record_id: &str, state: &str, time: i64) -> [u8; 32] {
+    Sha256::digest(format!("{}:{}:{}", record_id, state, time)).into()
+}
+
+/// Ed25519 public key from its 32 bytes
+pub(crate) fn parse_key(bytes: &[u8]) -> Result<VerifyingKey, Error> {
+    let bytes: &[u8; PUBLIC_KEY_LENGTH] = bytes.try_into().map_err(|_| {
+        Error::invalid_argument(
+            "public_key",
+            format!("an Ed25519 public key is {} bytes", PUBLIC_KEY_LENGTH),
+        )
+    })?;
+
+    VerifyingKey::from_bytes(bytes)
+        .map_err(|_| Error::invalid_argument("public_key", "not an Ed25519 public key"))
+}
+
+/// Check the signature of a digest with the registered key of the signer
+pub(crate) fn verify(key: &SignerKey, signature: &str, digest: &[u8; 32]) -> Result<(), Error> {
+    let signature = STANDARD
+        .decode(signature)
+        .ok()
+        .and_then(|bytes| Signature::from_slice(&bytes).ok())
+        .ok_or_else(|| {
+            Error::invalid_argument("signer.signature", "not a base64 Ed25519 signature")
+        })?;
+
+    let bytes = hex::decode(&key.public_key).map_err(|e| Error::Internal(e.to_string()))?;
+    let public_key = parse_key(&bytes).map_err(|e| Error::Internal(e.to_string()))?;
+
+    public_key
+        .verify_strict(digest, &signature)
+        .map_err(|_| Error::SignatureMismatch {
+            signer: key.signer.clone(),
+        })
+}
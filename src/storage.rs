@@ -0,0 +1,518 @@
+//! Storage abstraction for the Register
+//!
+//! Backends:
+//! - MongoDB (default, requires a replica set for watch)
+//! - Memory (demo and tests, nothing is persisted)
+
+mod memory;
+mod page;
+
+use std::{error::Error as StdError, pin::Pin, sync::Arc};
+
+use chrono::Utc;
+use mongodb::bson::{oid::ObjectId, Document};
+use tokio_stream::Stream;
+
+use crate::{
+    chain::Chain,
+    config::{AppConfig, StorageKind},
+    error::Error,
+    mongodb::{
+        Attachment, AuditEntry, Mongo, Record, RecordState, SignatureTraceFor, Signer, SignerKey,
+        StringId, TimeTraceFor, Trace, Transition,
+    },
+    workflow::{self, TraceField},
+};
+
+pub(crate) use self::memory::Memory;
+pub(crate) use self::page::{PageKey, Sort, SortField, SortValue};
+
+/// A stream of [Record] returned by a search
+pub(crate) type RecordStream = Pin<Box<dyn Stream<Item = Result<Record, Error>> + Send>>;
+
+/// Size of the chunks read from a stored file
+pub(crate) const CHUNK_SIZE: usize = 64 * 1024;
+
+/// A stream of the bytes of a stored file, by chunks
+pub(crate) type ByteStream = Pin<Box<dyn Stream<Item = Result<Vec<u8>, Error>> + Send>>;
+
+/// A stored file
+pub(crate) struct File {
+    pub(crate) content_type: String,
+    pub(crate) data: ByteStream,
+}
+
+/// A stream of [AuditEntry] returned by an audit query
+pub(crate) type AuditStream = Pin<Box<dyn Stream<Item = Result<AuditEntry, Error>> + Send>>;
+
+/// A stream of [ChangeEvent] returned by a watch
+pub(crate) trait ChangeFeed:
+    Stream<Item = Result<ChangeEvent, Error>> + Send + Unpin
+{
+    /// Token to resume a watch from the current position of the feed
+    ///
+    /// The position moves forward with the changes read, even the filtered ones.
+    fn resume_token(&self) -> Option<String>;
+}
+
+/// Records searched by state and creation time
+#[derive(Debug, Default)]
+pub(crate) struct SearchQuery {
+    pub(crate) states: Vec<RecordState>,
+    /// Creation time range (seconds, inclusive)
+    pub(crate) range: Option<(i64, i64)>,
+    /// Words searched in the summary and the signer names
+    pub(crate) text: Option<String>,
+    /// Time ranges of the traces (seconds, inclusive)
+    pub(crate) traces: Vec<(TraceField, (i64, i64))>,
+    /// Name of the client signer, of the collect or of the return
+    pub(crate) client_name: Option<String>,
+    /// Name of the pqrs signer, of the collect or of the return
+    pub(crate) pqrs_name: Option<String>,
+    pub(crate) sort: Sort,
+    /// Records just after this position
+    pub(crate) after: Option<PageKey>,
+    /// Maximum number of records, all if none
+    pub(crate) limit: Option<usize>,
+}
+
+impl SearchQuery {
+    /// Whether a record is found by the query
+    ///
+    /// The score of the record must be set for a text search.
+    pub(crate) fn matches(&self, record: &Record) -> bool {
+        let in_range = match (self.range, record.created) {
+            (None, _) => true,
+            (Some((begin, end)), Some(created)) => begin <= created && created <= end,
+            (Some(_), None) => false,
+        };
+
+        let in_trace_ranges = self.traces.iter().all(|(field, (begin, end))| {
+            trace_time(record, *field).is_some_and(|time| *begin <= time && time <= *end)
+        });
+        let signed_by = |name: &Option<String>, names: Vec<&str>| {
+            name.as_ref()
+                .is_none_or(|name| names.contains(&name.as_str()))
+        };
+
+        let has_text = self.text.is_none() || record.score.is_some_and(|score| score > 0.0);
+        let after = self.after.as_ref().is_none_or(|key| key.precedes(record));
+
+        self.states.contains(&record.state)
+            && in_range
+            && in_trace_ranges
+            && signed_by(&self.client_name, client_names(record).collect())
+            && signed_by(&self.pqrs_name, pqrs_names(record).collect())
+            && has_text
+            && after
+    }
+
+    /// Score of a record for the text search: number of searched words in the summary and the
+    /// signer names, case insensitive
+    pub(crate) fn score(&self, record: &Record) -> Option<f64> {
+        let text = self.text.as_ref()?.to_lowercase();
+
+        let content = std::iter::once(record.summary.as_str())
+            .chain(client_names(record))
+            .chain(pqrs_names(record))
+            .collect::<Vec<_>>()
+            .join(" ")
+            .to_lowercase();
+        let words: Vec<&str> = content
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .collect();
+
+        let score = text
+            .split_whitespace()
+            .map(|term| words.iter().filter(|word| **word == term).count())
+            .sum::<usize>();
+
+        Some(score as f64)
+    }
+}
+
+/// Length of the periods records are counted by
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Period {
+    Day,
+    /// Starts on monday
+    Week,
+}
+
+impl Period {
+    const DAY: i64 = 24 * 60 * 60;
+
+    /// Start of the period containing a time (seconds, UTC)
+    pub(crate) fn start(&self, time: i64) -> i64 {
+        let day = time.div_euclid(Period::DAY);
+
+        match self {
+            Period::Day => day * Period::DAY,
+            // 1970-01-01 is a thursday
+            Period::Week => (day - (day + 3).rem_euclid(7)) * Period::DAY,
+        }
+    }
+}
+
+/// Counts of the records found by a search
+#[derive(Debug, Default)]
+pub(crate) struct Stats {
+    /// By state, in state order
+    pub(crate) states: Vec<(RecordState, u64)>,
+    /// By start of creation period, in time order
+    pub(crate) periods: Vec<(i64, u64)>,
+}
+
+/// Traces of the collect and of the return of a record
+fn traces(record: &Record) -> impl Iterator<Item = &Trace> {
+    record
+        .traces
+        .iter()
+        .flat_map(|traces| [&traces.collected, &traces.returned].into_iter().flatten())
+}
+
+fn client_names(record: &Record) -> impl Iterator<Item = &str> {
+    traces(record).filter_map(|trace| trace.client.as_ref().map(|signer| signer.name.as_str()))
+}
+
+fn pqrs_names(record: &Record) -> impl Iterator<Item = &str> {
+    traces(record).filter_map(|trace| trace.pqrs.as_ref().map(|signer| signer.name.as_str()))
+}
+
+/// Time of a trace, none if the field is not a time
+fn trace_time(record: &Record, field: TraceField) -> Option<i64> {
+    let traces = record.traces.as_ref()?;
+
+    match field {
+        TraceField::CollectedInside => traces.collected.as_ref()?.inside,
+        TraceField::CollectedOutside => traces.collected.as_ref()?.outside,
+        TraceField::ReturnedInside => traces.returned.as_ref()?.inside,
+        TraceField::ReturnedOutside => traces.returned.as_ref()?.outside,
+        _ => None,
+    }
+}
+
+/// Records found by a search and the changes following the search
+pub(crate) struct Snapshot {
+    pub(crate) records: Vec<Record>,
+    /// Starts just after the search: no change is missing or already in the records
+    pub(crate) feed: Box<dyn ChangeFeed>,
+}
+
+/// Where a watch starts
+pub(crate) enum WatchStart {
+    Now,
+    /// Just after the change with this resume token
+    ResumeAfter(String),
+    /// At this time (seconds)
+    At(i64),
+}
+
+/// Kind of a change
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ChangeKind {
+    Added,
+    Modified,
+    Deleted,
+}
+
+/// Changes sent by a watch, an empty list doesn't filter
+#[derive(Clone, Debug, Default)]
+pub(crate) struct WatchFilter {
+    pub(crate) states: Vec<RecordState>,
+    pub(crate) ids: Vec<ObjectId>,
+    /// All kinds if none
+    pub(crate) kinds: Option<Vec<ChangeKind>>,
+}
+
+impl WatchFilter {
+    /// Whether a change passes the filter
+    ///
+    /// A deleted record has no state anymore: it passes if a deletable state is watched.
+    /// An invalidation always passes.
+    pub(crate) fn matches(&self, change: &Change) -> bool {
+        let (kind, id, state) = match change {
+            Change::Invalidated => return true,
+            Change::Added(record) => (ChangeKind::Added, record.id, Some(record.state)),
+            Change::Modified(record) => (ChangeKind::Modified, record.id, Some(record.state)),
+            Change::Deleted(id) => (ChangeKind::Deleted, Some(*id), None),
+        };
+
+        let kind_matches = self
+            .kinds
+            .as_ref()
+            .is_none_or(|kinds| kinds.contains(&kind));
+
+        kind_matches && self.matches_record(id, state)
+    }
+
+    /// Whether a record, or a deleted record without state, passes the id and state filters
+    pub(crate) fn matches_record(&self, id: Option<ObjectId>, state: Option<RecordState>) -> bool {
+        let id_matches = self.ids.is_empty() || id.is_some_and(|id| self.ids.contains(&id));
+        let state_matches = self.states.is_empty()
+            || match state {
+                Some(state) => self.states.contains(&state),
+                None => self.watches_deletable(),
+            };
+
+        id_matches && state_matches
+    }
+
+    /// Whether a watched state allows deletion
+    pub(crate) fn watches_deletable(&self) -> bool {
+        self.states
+            .iter()
+            .any(|state| workflow::DELETABLE.contains(state))
+    }
+}
+
+/// A change with the token to resume a watch just after it
+#[derive(Debug)]
+pub(crate) struct ChangeEvent {
+    pub(crate) change: Change,
+    pub(crate) resume_token: String,
+}
+
+/// A change that occurred on the register
+#[derive(Clone, Debug)]
+pub(crate) enum Change {
+    Added(Record),
+    Modified(Record),
+    Deleted(ObjectId),
+    /// The register can't be watched anymore
+    Invalidated,
+}
+
+/// Audit entries to find, oldest first. None doesn't filter
+#[derive(Debug, Default)]
+pub(crate) struct AuditQuery {
+    /// Seconds, inclusive
+    pub(crate) range: Option<(i64, i64)>,
+    pub(crate) record_id: Option<String>,
+}
+
+impl AuditQuery {
+    pub(crate) fn matches(&self, entry: &AuditEntry) -> bool {
+        let range_matches = self
+            .range
+            .is_none_or(|(begin, end)| begin <= entry.time && entry.time <= end);
+        let record_matches = self
+            .record_id
+            .as_ref()
+            .is_none_or(|id| entry.record_id.as_ref() == Some(id));
+
+        range_matches && record_matches
+    }
+}
+
+/// Who updates a record and how, written in its history
+#[derive(Clone, Debug)]
+pub(crate) struct Origin {
+    pub(crate) caller: String,
+    /// Name of the RPC (eg: `SubmitDraft`)
+    pub(crate) rpc: &'static str,
+}
+
+impl Origin {
+    /// History entry of an update now, not linked to the hash chain yet
+    pub(crate) fn transition(
+        &self,
+        from: Option<RecordState>,
+        to: RecordState,
+        set: Document,
+    ) -> Transition {
+        Transition {
+            from,
+            to,
+            time: Utc::now().timestamp(),
+            caller: self.caller.clone(),
+            rpc: self.rpc.to_owned(),
+            set,
+            hash: None,
+        }
+    }
+}
+
+/// Operations required by the Register service
+///
+/// Every update is appended to the record history with its [Origin].
+#[tonic::async_trait]
+pub(crate) trait Storage: Send + Sync {
+    async fn insert_draft(&self, summary: String, origin: Origin) -> Result<ObjectId, Error>;
+
+    async fn update_draft(
+        &self,
+        id: StringId,
+        summary: String,
+        origin: Origin,
+    ) -> Result<(), Error>;
+
+    async fn delete_draft(&self, id: StringId) -> Result<(), Error>;
+
+    async fn submit_draft(&self, id: StringId, origin: Origin) -> Result<(), Error>;
+
+    async fn client_time_trace(
+        &self,
+        id: StringId,
+        time: i64,
+        target: TimeTraceFor,
+        origin: Origin,
+    ) -> Result<(), Error>;
+
+    async fn signature_trace(
+        &self,
+        id: StringId,
+        signer: Signer,
+        target: SignatureTraceFor,
+        origin: Origin,
+    ) -> Result<(), Error>;
+
+    async fn completed(&self, id: StringId, origin: Origin) -> Result<(), Error>;
+
+    async fn search(&self, query: SearchQuery) -> Result<RecordStream, Error>;
+
+    /// Records in a state since before the limit (seconds) of this state
+    async fn overdue(&self, limits: Vec<(RecordState, i64)>) -> Result<RecordStream, Error>;
+
+    /// Append a call to the audit trail, never updated nor deleted
+    async fn append_audit(&self, entry: AuditEntry) -> Result<(), Error>;
+
+    async fn audit(&self, query: AuditQuery) -> Result<AuditStream, Error>;
+
+    /// Store a signature image, referenced by its signer
+    async fn insert_image(&self, content_type: String, data: Vec<u8>) -> Result<ObjectId, Error>;
+
+    async fn delete_image(&self, id: ObjectId) -> Result<(), Error>;
+
+    async fn image(&self, id: StringId) -> Result<Option<File>, Error>;
+
+    /// Attach a document to a record, its content is read until the end of the stream
+    async fn insert_attachment(
+        &self,
+        id: StringId,
+        filename: String,
+        content_type: String,
+        data: ByteStream,
+        origin: Origin,
+    ) -> Result<Attachment, Error>;
+
+    async fn delete_attachment(
+        &self,
+        id: StringId,
+        attachment: StringId,
+        origin: Origin,
+    ) -> Result<(), Error>;
+
+    async fn attachment(&self, id: StringId, attachment: StringId) -> Result<Option<File>, Error>;
+
+    /// Register the public key of a signer, replacing the previous one
+    async fn register_key(&self, key: SignerKey) -> Result<(), Error>;
+
+    async fn signer_key(&self, signer: &str) -> Result<Option<SignerKey>, Error>;
+
+    async fn search_by_id(&self, id: StringId) -> Result<Option<Record>, Error>;
+
+    async fn stats(&self, query: SearchQuery, period: Period) -> Result<Stats, Error>;
+
+    async fn watch(
+        &self,
+        start: WatchStart,
+        filter: WatchFilter,
+    ) -> Result<Box<dyn ChangeFeed>, Error>;
+
+    /// Search records then watch their changes
+    async fn snapshot(&self, query: SearchQuery, filter: WatchFilter) -> Result<Snapshot, Error>;
+}
+
+/// Build the storage backend selected by the configuration
+pub(crate) async fn from_config(
+    config: &AppConfig,
+    chain: Chain,
+) -> Result<Arc<dyn Storage>, Box<dyn StdError>> {
+    tracing::info!("use storage: {:?}", config.storage);
+
+    match config.storage {
+        StorageKind::Mongodb => {
+            let mongodb = config
+                .mongodb
+                .as_ref()
+                .ok_or("mongodb configuration is required by mongodb storage")?;
+
+            Ok(Arc::new(Mongo::new(mongodb, chain).await?))
+        }
+        StorageKind::Memory => Ok(Arc::new(Memory::new(chain))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn time(year: i32, month: u32, day: u32, hour: u32) -> i64 {
+        Utc.with_ymd_and_hms(year, month, day, hour, 30, 0)
+            .single()
+            .expect("valid time")
+            .timestamp()
+    }
+
+    fn midnight(year: i32, month: u32, day: u32) -> i64 {
+        Utc.with_ymd_and_hms(year, month, day, 0, 0, 0)
+            .single()
+            .expect("valid time")
+            .timestamp()
+    }
+
+    #[test]
+    fn day_starts_at_midnight() {
+        assert_eq!(
+            Period::Day.start(time(2024, 6, 5, 15)),
+            midnight(2024, 6, 5)
+        );
+        assert_eq!(
+            Period::Day.start(midnight(2024, 6, 5)),
+            midnight(2024, 6, 5)
+        );
+    }
+
+    #[test]
+    fn week_of_a_monday_starts_that_day() {
+        // 2024-06-03 is a monday
+        assert_eq!(
+            Period::Week.start(time(2024, 6, 3, 0)),
+            midnight(2024, 6, 3)
+        );
+        assert_eq!(
+            Period::Week.start(midnight(2024, 6, 3)),
+            midnight(2024, 6, 3)
+        );
+    }
+
+    #[test]
+    fn week_of_a_sunday_starts_the_monday_before() {
+        assert_eq!(
+            Period::Week.start(time(2024, 6, 9, 23)),
+            midnight(2024, 6, 3)
+        );
+        assert_eq!(
+            Period::Week.start(midnight(2024, 6, 10)),
+            midnight(2024, 6, 10)
+        );
+    }
+
+    #[test]
+    fn period_of_a_time_before_the_epoch() {
+        // 1969-12-31 is a wednesday
+        let wednesday = time(1969, 12, 31, 12);
+        assert!(wednesday < 0);
+
+        assert_eq!(Period::Day.start(wednesday), midnight(1969, 12, 31));
+        assert_eq!(Period::Week.start(wednesday), midnight(1969, 12, 29));
+        assert_eq!(
+            Period::Week.start(time(1970, 1, 1, 12)),
+            midnight(1969, 12, 29)
+        );
+    }
+}
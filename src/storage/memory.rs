@@ -0,0 +1,936 @@
+//! In-memory storage
+//!
+//! Records are lost when the service is stopped. Watch is backed by a broadcast channel
+//! and the last changes are kept to resume a watch.
+
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    pin::Pin,
+    sync::{Mutex, MutexGuard},
+    task::{ready, Context, Poll},
+};
+
+use chrono::Utc;
+use mongodb::bson::{doc, oid::ObjectId, Document};
+use num_traits::FromPrimitive;
+use sha2::{Digest, Sha256};
+use tokio::sync::broadcast;
+use tokio_stream::{wrappers::BroadcastStream, Stream, StreamExt};
+
+use super::{
+    AuditQuery, AuditStream, ByteStream, Change, ChangeEvent, ChangeFeed, File, Origin, Period,
+    RecordStream, SearchQuery, Snapshot, Stats, Storage, WatchFilter, WatchStart, CHUNK_SIZE,
+};
+use crate::{
+    chain::{self, Chain},
+    error::Error,
+    mongodb::{
+        Attachment, AuditEntry, Mongo, Record, RecordState, SignatureTraceFor, Signer, SignerKey,
+        StringId, TimeTraceFor,
+    },
+    workflow::{self, Action, Payload},
+};
+
+static API_VERSION_1: i32 = 1;
+
+/// Number of changes kept for slow watchers before they lag
+const CHANGES_CAPACITY: usize = 128;
+
+/// Number of changes kept to resume a watch
+const HISTORY_CAPACITY: usize = 1024;
+
+pub(crate) struct Memory {
+    state: Mutex<State>,
+    changes: broadcast::Sender<Logged>,
+    chain: Chain,
+}
+
+struct State {
+    records: BTreeMap<ObjectId, Record>,
+    /// Sequence of the last change
+    sequence: u64,
+    /// Last changes, oldest first
+    history: VecDeque<Logged>,
+    /// Sequence and time of the last change dropped from the history
+    dropped: Option<(u64, i64)>,
+    /// Audit trail, oldest first
+    audit: Vec<AuditEntry>,
+    /// Public keys by signer name
+    keys: HashMap<String, SignerKey>,
+    /// Signature images with their content type
+    images: HashMap<ObjectId, (String, Vec<u8>)>,
+    /// Content of the attachments, listed by their records
+    attachments: HashMap<ObjectId, Vec<u8>>,
+}
+
+/// A change with its position
+#[derive(Clone)]
+struct Logged {
+    sequence: u64,
+    time: i64,
+    change: Change,
+}
+
+/// Changes of a watch, the resume token is the sequence of a change
+struct Feed {
+    changes: Pin<Box<dyn Stream<Item = Result<Logged, Error>> + Send>>,
+    filter: WatchFilter,
+    /// Sequence of the last change read
+    position: u64,
+}
+
+impl Stream for Feed {
+    type Item = Result<ChangeEvent, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            let logged = match ready!(self.changes.as_mut().poll_next(cx)) {
+                Some(Ok(logged)) => logged,
+                Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+                None => return Poll::Ready(None),
+            };
+
+            self.position = logged.sequence;
+
+            if self.filter.matches(&logged.change) {
+                return Poll::Ready(Some(Ok(ChangeEvent {
+                    change: logged.change,
+                    resume_token: logged.sequence.to_string(),
+                })));
+            }
+        }
+    }
+}
+
+impl ChangeFeed for Feed {
+    fn resume_token(&self) -> Option<String> {
+        Some(self.position.to_string())
+    }
+}
+
+impl Memory {
+    pub(crate) fn new(chain: Chain) -> Self {
+        let (changes, _) = broadcast::channel(CHANGES_CAPACITY);
+
+        Self {
+            state: Mutex::new(State {
+                records: BTreeMap::new(),
+                sequence: 0,
+                history: VecDeque::with_capacity(HISTORY_CAPACITY),
+                dropped: None,
+                audit: vec![],
+                keys: HashMap::new(),
+                images: HashMap::new(),
+                attachments: HashMap::new(),
+            }),
+            changes,
+            chain,
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("memory storage lock poisoned")
+    }
+
+    /// A record allowing documents to be attached or removed
+    fn attachable<'a>(state: &'a mut State, id: &StringId) -> Result<&'a mut Record, Error> {
+        let oid = id.to_object_id()?;
+        let record = state
+            .records
+            .get_mut(&oid)
+            .ok_or_else(|| Error::not_found(id))?;
+
+        if !workflow::ATTACHABLE.contains(&record.state) {
+            return Err(Error::FailedPrecondition {
+                id: id.to_string(),
+                current: record.state,
+                expected: workflow::ATTACHABLE.to_vec(),
+            });
+        }
+
+        Ok(record)
+    }
+
+    /// Bytes of a stored file, by chunks
+    fn read_chunks(data: &[u8]) -> ByteStream {
+        Box::pin(tokio_stream::iter(
+            data.chunks(CHUNK_SIZE)
+                .map(|chunk| Ok(chunk.to_vec()))
+                .collect::<Vec<_>>(),
+        ))
+    }
+
+    /// Log a change and send it to watchers
+    ///
+    /// The state lock must be held so watchers never miss or duplicate a change.
+    fn notify(&self, state: &mut State, change: Change) {
+        state.sequence += 1;
+
+        let logged = Logged {
+            sequence: state.sequence,
+            time: Utc::now().timestamp(),
+            change,
+        };
+
+        if state.history.len() == HISTORY_CAPACITY {
+            state.dropped = state
+                .history
+                .pop_front()
+                .map(|oldest| (oldest.sequence, oldest.time));
+        }
+        state.history.push_back(logged.clone());
+
+        // no receiver is not an error; nobody is watching
+        let _ = self.changes.send(logged);
+    }
+
+    /// Changes to replay for a watch starting in the past
+    fn replay(state: &State, start: &WatchStart) -> Result<Vec<Logged>, Error> {
+        let is_replayed: Box<dyn Fn(&Logged) -> bool> = match start {
+            WatchStart::Now => return Ok(vec![]),
+            WatchStart::ResumeAfter(token) => {
+                let after: u64 = token
+                    .parse()
+                    .map_err(|_| Error::invalid_argument("resume_token", "malformed token"))?;
+
+                if after > state.sequence {
+                    return Err(Error::invalid_argument("resume_token", "unknown token"));
+                }
+                if matches!(state.dropped, Some((dropped, _)) if after < dropped) {
+                    return Err(Error::ResumePointExpired(token.clone()));
+                }
+
+                Box::new(move |logged| logged.sequence > after)
+            }
+            WatchStart::At(time) => {
+                let time = *time;
+
+                if matches!(state.dropped, Some((_, dropped)) if time <= dropped) {
+                    return Err(Error::ResumePointExpired(time.to_string()));
+                }
+
+                Box::new(move |logged| logged.time >= time)
+            }
+        };
+
+        Ok(state
+            .history
+            .iter()
+            .filter(|logged| is_replayed(logged))
+            .cloned()
+            .collect())
+    }
+
+    /// Records found by a search, unsorted
+    fn found(state: &State, query: &SearchQuery) -> Vec<Record> {
+        state
+            .records
+            .values()
+            .map(|record| Record {
+                score: query.score(record),
+                ..record.clone()
+            })
+            .filter(|record| query.matches(record))
+            .collect()
+    }
+
+    /// Feed of the replayed changes followed by the live changes
+    ///
+    /// The state lock must be held so no change is sent between the replay and the live changes.
+    fn follow(&self, state: &State, replayed: Vec<Logged>, filter: WatchFilter) -> Feed {
+        let position = replayed
+            .first()
+            .map_or(state.sequence, |logged| logged.sequence - 1);
+
+        let changes = BroadcastStream::new(self.changes.subscribe())
+            .map(|logged| logged.map_err(|e| Error::Unavailable(e.to_string())));
+
+        Feed {
+            changes: Box::pin(tokio_stream::iter(replayed).map(Ok).chain(changes)),
+            filter,
+            position,
+        }
+    }
+
+    /// Apply a workflow step on a record
+    ///
+    /// The record is updated only if its state is allowed by the step.
+    fn transition(
+        &self,
+        id: StringId,
+        action: Action,
+        payload: Payload,
+        origin: Origin,
+    ) -> Result<(), Error> {
+        let step = workflow::step(action);
+
+        step.check_guards(&payload)
+            .map_err(|violation| Error::violation(&id, violation))?;
+
+        let oid = id.to_object_id()?;
+        let mut state = self.state();
+
+        let record = state
+            .records
+            .get_mut(&oid)
+            .ok_or_else(|| Error::not_found(&id))?;
+
+        step.check_state(record.state)
+            .map_err(|violation| Error::violation(&id, violation))?;
+
+        // the fields are written as by the mongodb storage, so the history replays them
+        let set = match step.writes {
+            Some(field) => Mongo::write(field, &payload, record)?,
+            None => Document::new(),
+        };
+        let entry = self.chain.linked(
+            &record.history,
+            origin.transition(Some(record.state), step.to, set),
+        )?;
+
+        chain::apply_to_record(record, &entry.set)?;
+        if step.changes_state() {
+            record.transitioned = Some(Utc::now().timestamp());
+        }
+        if step.to == workflow::SEALED {
+            record.seal = entry.hash.clone();
+        }
+        record.history.push(entry);
+        record.state = step.to;
+
+        let change = Change::Modified(record.clone());
+        self.notify(&mut state, change);
+
+        Ok(())
+    }
+}
+
+#[tonic::async_trait]
+impl Storage for Memory {
+    async fn insert_draft(&self, summary: String, origin: Origin) -> Result<ObjectId, Error> {
+        let id = ObjectId::new();
+        let created = Utc::now().timestamp();
+        let set = doc! {
+            "summary": &summary,
+            "created": created,
+        };
+        let draft = Record {
+            id: Some(id),
+            api_version: API_VERSION_1,
+            created: Some(created),
+            summary,
+            traces: None,
+            state: workflow::INITIAL,
+            transitioned: None,
+            history: vec![self
+                .chain
+                .linked(&[], origin.transition(None, workflow::INITIAL, set))?],
+            seal: None,
+            attachments: vec![],
+            score: None,
+        };
+
+        let mut state = self.state();
+
+        state.records.insert(id, draft.clone());
+        self.notify(&mut state, Change::Added(draft));
+
+        Ok(id)
+    }
+
+    async fn update_draft(
+        &self,
+        id: StringId,
+        summary: String,
+        origin: Origin,
+    ) -> Result<(), Error> {
+        self.transition(id, Action::UpdateDraft, Payload::Summary(summary), origin)
+    }
+
+    async fn delete_draft(&self, id: StringId) -> Result<(), Error> {
+        let oid = id.to_object_id()?;
+        let mut state = self.state();
+
+        let record = state
+            .records
+            .get(&oid)
+            .ok_or_else(|| Error::not_found(&id))?;
+
+        if !workflow::DELETABLE.contains(&record.state) {
+            return Err(Error::FailedPrecondition {
+                id: id.to_string(),
+                current: record.state,
+                expected: workflow::DELETABLE.to_vec(),
+            });
+        }
+
+        if let Some(record) = state.records.remove(&oid) {
+            for attachment in record.attachments {
+                state.attachments.remove(&attachment.id);
+            }
+        }
+        self.notify(&mut state, Change::Deleted(oid));
+
+        Ok(())
+    }
+
+    async fn submit_draft(&self, id: StringId, origin: Origin) -> Result<(), Error> {
+        self.transition(id, Action::SubmitDraft, Payload::None, origin)
+    }
+
+    async fn client_time_trace(
+        &self,
+        id: StringId,
+        time: i64,
+        target: TimeTraceFor,
+        origin: Origin,
+    ) -> Result<(), Error> {
+        self.transition(id, target.into(), Payload::Time(time), origin)
+    }
+
+    async fn signature_trace(
+        &self,
+        id: StringId,
+        signer: Signer,
+        target: SignatureTraceFor,
+        origin: Origin,
+    ) -> Result<(), Error> {
+        self.transition(id, target.into(), Payload::Signer(signer), origin)
+    }
+
+    async fn completed(&self, id: StringId, origin: Origin) -> Result<(), Error> {
+        self.transition(id, Action::Complete, Payload::None, origin)
+    }
+
+    async fn search(&self, query: SearchQuery) -> Result<RecordStream, Error> {
+        let mut found = Memory::found(&self.state(), &query);
+
+        found.sort_by(|a, b| query.sort.compare(a, b));
+        if let Some(limit) = query.limit {
+            found.truncate(limit);
+        }
+
+        Ok(Box::pin(tokio_stream::iter(found).map(Ok)))
+    }
+
+    async fn stats(&self, query: SearchQuery, period: Period) -> Result<Stats, Error> {
+        let mut states = BTreeMap::new();
+        let mut periods = BTreeMap::new();
+
+        for record in Memory::found(&self.state(), &query) {
+            *states.entry(record.state as i32).or_insert(0) += 1;
+
+            if let Some(created) = record.created {
+                *periods.entry(period.start(created)).or_insert(0) += 1;
+            }
+        }
+
+        Ok(Stats {
+            states: states
+                .into_iter()
+                .filter_map(|(state, count)| Some((RecordState::from_i32(state)?, count)))
+                .collect(),
+            periods: periods.into_iter().collect(),
+        })
+    }
+
+    async fn overdue(&self, limits: Vec<(RecordState, i64)>) -> Result<RecordStream, Error> {
+        let found: Vec<Record> = self
+            .state()
+            .records
+            .values()
+            .filter(|record| {
+                limits.iter().any(|(state, limit)| {
+                    record.state == *state
+                        && record.in_state_since().is_some_and(|since| since < *limit)
+                })
+            })
+            .cloned()
+            .collect();
+
+        Ok(Box::pin(tokio_stream::iter(found).map(Ok)))
+    }
+
+    async fn append_audit(&self, entry: AuditEntry) -> Result<(), Error> {
+        self.state().audit.push(AuditEntry {
+            id: Some(ObjectId::new()),
+            ..entry
+        });
+
+        Ok(())
+    }
+
+    async fn audit(&self, query: AuditQuery) -> Result<AuditStream, Error> {
+        let found: Vec<AuditEntry> = self
+            .state()
+            .audit
+            .iter()
+            .filter(|entry| query.matches(entry))
+            .cloned()
+            .collect();
+
+        Ok(Box::pin(tokio_stream::iter(found).map(Ok)))
+    }
+
+    async fn insert_image(&self, content_type: String, data: Vec<u8>) -> Result<ObjectId, Error> {
+        let id = ObjectId::new();
+        self.state().images.insert(id, (content_type, data));
+
+        Ok(id)
+    }
+
+    async fn delete_image(&self, id: ObjectId) -> Result<(), Error> {
+        self.state().images.remove(&id);
+
+        Ok(())
+    }
+
+    async fn image(&self, id: StringId) -> Result<Option<File>, Error> {
+        let id = id.to_object_id()?;
+
+        Ok(self
+            .state()
+            .images
+            .get(&id)
+            .map(|(content_type, data)| File {
+                content_type: content_type.clone(),
+                data: Memory::read_chunks(data),
+            }))
+    }
+
+    async fn insert_attachment(
+        &self,
+        id: StringId,
+        filename: String,
+        content_type: String,
+        mut data: ByteStream,
+        origin: Origin,
+    ) -> Result<Attachment, Error> {
+        // fail before reading the content
+        Memory::attachable(&mut self.state(), &id)?;
+
+        let mut content = Vec::new();
+        while let Some(chunk) = data.next().await {
+            content.extend(chunk?);
+        }
+
+        let attachment = Attachment {
+            id: ObjectId::new(),
+            filename,
+            content_type,
+            length: content.len() as i64,
+            sha256: hex::encode(Sha256::digest(&content)),
+            uploaded: Utc::now().timestamp(),
+        };
+
+        // the content is stored before the record lists it
+        self.state().attachments.insert(attachment.id, content);
+
+        // the record may have been submitted during the upload
+        let listed = self.transition(
+            id,
+            Action::UploadAttachment,
+            Payload::Attach(attachment.clone()),
+            origin,
+        );
+
+        if let Err(e) = listed {
+            self.state().attachments.remove(&attachment.id);
+            return Err(e);
+        }
+
+        Ok(attachment)
+    }
+
+    async fn delete_attachment(
+        &self,
+        id: StringId,
+        attachment: StringId,
+        origin: Origin,
+    ) -> Result<(), Error> {
+        let attachment = attachment.to_object_id()?;
+
+        self.transition(
+            id,
+            Action::DeleteAttachment,
+            Payload::Detach(attachment),
+            origin,
+        )?;
+
+        self.state().attachments.remove(&attachment);
+
+        Ok(())
+    }
+
+    async fn attachment(&self, id: StringId, attachment: StringId) -> Result<Option<File>, Error> {
+        let oid = id.to_object_id()?;
+        let attachment = attachment.to_object_id()?;
+        let state = self.state();
+
+        let record = state
+            .records
+            .get(&oid)
+            .ok_or_else(|| Error::not_found(&id))?;
+        let Some(listed) = record
+            .attachments
+            .iter()
+            .find(|listed| listed.id == attachment)
+        else {
+            return Ok(None);
+        };
+
+        Ok(Some(File {
+            content_type: listed.content_type.clone(),
+            data: Memory::read_chunks(
+                state
+                    .attachments
+                    .get(&attachment)
+                    .map(Vec::as_slice)
+                    .unwrap_or_default(),
+            ),
+        }))
+    }
+
+    async fn register_key(&self, key: SignerKey) -> Result<(), Error> {
+        self.state().keys.insert(key.signer.clone(), key);
+
+        Ok(())
+    }
+
+    async fn signer_key(&self, signer: &str) -> Result<Option<SignerKey>, Error> {
+        Ok(self.state().keys.get(signer).cloned())
+    }
+
+    async fn search_by_id(&self, id: StringId) -> Result<Option<Record>, Error> {
+        let id = id.to_object_id()?;
+
+        Ok(self.state().records.get(&id).cloned())
+    }
+
+    async fn watch(
+        &self,
+        start: WatchStart,
+        filter: WatchFilter,
+    ) -> Result<Box<dyn ChangeFeed>, Error> {
+        let state = self.state();
+        let replayed = Memory::replay(&state, &start)?;
+
+        Ok(Box::new(self.follow(&state, replayed, filter)))
+    }
+
+    async fn snapshot(&self, query: SearchQuery, filter: WatchFilter) -> Result<Snapshot, Error> {
+        let state = self.state();
+        let records = Memory::found(&state, &query);
+
+        Ok(Snapshot {
+            records,
+            feed: Box::new(self.follow(&state, vec![], filter)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::storage::ChangeKind;
+
+    fn memory() -> Memory {
+        Memory::new(Chain::new(b""))
+    }
+
+    fn origin() -> Origin {
+        Origin {
+            caller: "test".to_owned(),
+            rpc: "Test",
+        }
+    }
+
+    async fn draft(memory: &Memory, summary: &str) -> ObjectId {
+        memory
+            .insert_draft(summary.to_owned(), origin())
+            .await
+            .expect("draft created")
+    }
+
+    /// Kind, id and resume token of a change
+    fn describe(event: ChangeEvent) -> (ChangeKind, ObjectId, String) {
+        let (kind, id) = match event.change {
+            Change::Added(record) => (ChangeKind::Added, record.id),
+            Change::Modified(record) => (ChangeKind::Modified, record.id),
+            Change::Deleted(id) => (ChangeKind::Deleted, Some(id)),
+            Change::Invalidated => panic!("watch invalidated"),
+        };
+
+        (kind, id.expect("record id"), event.resume_token)
+    }
+
+    /// Changes sent by a feed until it waits for the next one
+    async fn received(feed: &mut Box<dyn ChangeFeed>) -> Vec<(ChangeKind, ObjectId)> {
+        let mut received = vec![];
+
+        while let Ok(Some(event)) =
+            tokio::time::timeout(Duration::from_millis(50), feed.next()).await
+        {
+            let (kind, id, _) = describe(event.expect("change"));
+            received.push((kind, id));
+        }
+
+        received
+    }
+
+    #[tokio::test]
+    async fn watch_resumes_after_a_token() {
+        let memory = memory();
+        let mut feed = memory
+            .watch(WatchStart::Now, WatchFilter::default())
+            .await
+            .expect("watching");
+
+        let first = draft(&memory, "first").await;
+        let event = feed.next().await.expect("change").expect("change");
+        let (_, id, token) = describe(event);
+        assert_eq!(id, first);
+        drop(feed);
+
+        // changes while disconnected
+        let second = draft(&memory, "second").await;
+        memory
+            .update_draft(StringId(first.to_hex()), "updated".to_owned(), origin())
+            .await
+            .expect("draft updated");
+
+        let mut feed = memory
+            .watch(WatchStart::ResumeAfter(token), WatchFilter::default())
+            .await
+            .expect("watching");
+        let third = draft(&memory, "third").await;
+
+        assert_eq!(
+            received(&mut feed).await,
+            [
+                (ChangeKind::Added, second),
+                (ChangeKind::Modified, first),
+                (ChangeKind::Added, third),
+            ]
+        );
+        assert_eq!(feed.resume_token().as_deref(), Some("4"));
+    }
+
+    #[tokio::test]
+    async fn expired_token_is_rejected() {
+        let memory = memory();
+        draft(&memory, "first").await;
+
+        for _ in 0..HISTORY_CAPACITY {
+            draft(&memory, "next").await;
+        }
+
+        let e = memory
+            .watch(
+                WatchStart::ResumeAfter("0".to_owned()),
+                WatchFilter::default(),
+            )
+            .await
+            .err()
+            .expect("expired token resumed");
+        assert!(matches!(e, Error::ResumePointExpired(_)), "{:?}", e);
+
+        // the oldest change kept
+        memory
+            .watch(
+                WatchStart::ResumeAfter("1".to_owned()),
+                WatchFilter::default(),
+            )
+            .await
+            .expect("watching");
+
+        let e = memory
+            .watch(
+                WatchStart::ResumeAfter("9999".to_owned()),
+                WatchFilter::default(),
+            )
+            .await
+            .err()
+            .expect("unknown token resumed");
+        assert!(matches!(e, Error::InvalidArgument { .. }), "{:?}", e);
+    }
+
+    /// Changes of a scenario passing a filter: a draft submitted, a draft updated then deleted
+    async fn filtered(filter: WatchFilter) -> (Vec<(ChangeKind, ObjectId)>, ObjectId, ObjectId) {
+        let memory = memory();
+        let submitted = draft(&memory, "submitted").await;
+        let deleted = draft(&memory, "deleted").await;
+
+        let mut feed = memory
+            .watch(WatchStart::Now, filter)
+            .await
+            .expect("watching");
+
+        memory
+            .submit_draft(StringId(submitted.to_hex()), origin())
+            .await
+            .expect("draft submitted");
+        memory
+            .update_draft(StringId(deleted.to_hex()), "updated".to_owned(), origin())
+            .await
+            .expect("draft updated");
+        memory
+            .delete_draft(StringId(deleted.to_hex()))
+            .await
+            .expect("draft deleted");
+
+        (received(&mut feed).await, submitted, deleted)
+    }
+
+    #[tokio::test]
+    async fn watch_filters_by_state() {
+        let (changes, submitted, _) = filtered(WatchFilter {
+            states: vec![RecordState::Created],
+            ..Default::default()
+        })
+        .await;
+        // a deleted draft isn't in a watched state
+        assert_eq!(changes, [(ChangeKind::Modified, submitted)]);
+
+        let (changes, _, deleted) = filtered(WatchFilter {
+            states: vec![RecordState::Draft],
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(
+            changes,
+            [
+                (ChangeKind::Modified, deleted),
+                (ChangeKind::Deleted, deleted)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn watch_filters_by_id() {
+        let memory = memory();
+        let watched = draft(&memory, "watched").await;
+        let other = draft(&memory, "other").await;
+
+        let filter = WatchFilter {
+            ids: vec![watched],
+            ..Default::default()
+        };
+        let mut feed = memory
+            .watch(WatchStart::Now, filter)
+            .await
+            .expect("watching");
+
+        for id in [other, watched] {
+            memory
+                .submit_draft(StringId(id.to_hex()), origin())
+                .await
+                .expect("draft submitted");
+        }
+
+        assert_eq!(received(&mut feed).await, [(ChangeKind::Modified, watched)]);
+        // the filtered changes move the position too
+        assert_eq!(feed.resume_token().as_deref(), Some("4"));
+    }
+
+    #[tokio::test]
+    async fn watch_filters_by_kind() {
+        let (changes, _, deleted) = filtered(WatchFilter {
+            kinds: Some(vec![ChangeKind::Deleted]),
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(changes, [(ChangeKind::Deleted, deleted)]);
+
+        let (changes, submitted, deleted) = filtered(WatchFilter {
+            kinds: Some(vec![ChangeKind::Modified]),
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(
+            changes,
+            [
+                (ChangeKind::Modified, submitted),
+                (ChangeKind::Modified, deleted)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn snapshot_is_followed_by_the_next_changes() {
+        let memory = memory();
+        let first = draft(&memory, "first").await;
+        let second = draft(&memory, "second").await;
+
+        let query = SearchQuery {
+            states: vec![RecordState::Draft],
+            ..Default::default()
+        };
+        let Snapshot { records, mut feed } = memory
+            .snapshot(query, WatchFilter::default())
+            .await
+            .expect("snapshot");
+
+        let mut ids: Vec<_> = records.iter().filter_map(|record| record.id).collect();
+        ids.sort();
+        let mut expected = vec![first, second];
+        expected.sort();
+        assert_eq!(ids, expected);
+
+        let third = draft(&memory, "third").await;
+        memory
+            .submit_draft(StringId(first.to_hex()), origin())
+            .await
+            .expect("draft submitted");
+
+        // neither the records of the snapshot again, nor a gap
+        assert_eq!(
+            received(&mut feed).await,
+            [(ChangeKind::Added, third), (ChangeKind::Modified, first)]
+        );
+    }
+
+    #[tokio::test]
+    async fn stats_count_by_state_and_period() {
+        let memory = memory();
+        // a monday, the sunday before, and the next day
+        let monday = 1_717_372_800;
+        let day = Period::DAY;
+
+        for (created, submitted) in [
+            (monday + 10, true),
+            (monday + day - 1, false),
+            (monday - 1, false),
+            (monday + day, false),
+        ] {
+            let id = draft(&memory, "record").await;
+            if submitted {
+                memory
+                    .submit_draft(StringId(id.to_hex()), origin())
+                    .await
+                    .expect("draft submitted");
+            }
+            memory.state().records.get_mut(&id).expect("record").created = Some(created);
+        }
+
+        let query = || SearchQuery {
+            states: vec![RecordState::Draft, RecordState::Created],
+            ..Default::default()
+        };
+
+        let stats = memory.stats(query(), Period::Day).await.expect("stats");
+        assert_eq!(
+            stats.states,
+            [(RecordState::Draft, 3), (RecordState::Created, 1)]
+        );
+        assert_eq!(
+            stats.periods,
+            [(monday - day, 1), (monday, 2), (monday + day, 1)]
+        );
+
+        let stats = memory.stats(query(), Period::Week).await.expect("stats");
+        assert_eq!(stats.periods, [(monday - 7 * day, 1), (monday, 3)]);
+    }
+}
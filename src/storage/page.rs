@@ -0,0 +1,317 @@
+//! Sorted and paginated searches
+//!
+//! Pages are cut by keyset: a page token is the position of the last record of the previous page,
+//! made of the sort field value, the creation time and the id of the record.
+
+use std::cmp::Ordering;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use mongodb::bson::{self, oid::ObjectId};
+use serde::{Deserialize, Serialize};
+
+use crate::{error::Error, mongodb::Record};
+
+/// Field a search is sorted by, then by creation time and id
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum SortField {
+    #[default]
+    Created,
+    State,
+    Summary,
+    /// Score of a text search
+    Relevance,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Sort {
+    pub(crate) field: SortField,
+    pub(crate) descending: bool,
+}
+
+/// Position of a record in a sorted search
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct PageKey {
+    pub(crate) field: SortField,
+    pub(crate) descending: bool,
+    pub(crate) value: SortValue,
+    pub(crate) created: i64,
+    pub(crate) id: ObjectId,
+}
+
+/// Value of the sort field, none if sorted by creation time
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) enum SortValue {
+    None,
+    State(i32),
+    Summary(String),
+    Relevance(f64),
+}
+
+impl SortValue {
+    fn rank(&self) -> u8 {
+        match self {
+            SortValue::None => 0,
+            SortValue::State(_) => 1,
+            SortValue::Summary(_) => 2,
+            SortValue::Relevance(_) => 3,
+        }
+    }
+}
+
+impl PartialEq for SortValue {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for SortValue {}
+
+impl PartialOrd for SortValue {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for SortValue {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (SortValue::State(a), SortValue::State(b)) => a.cmp(b),
+            (SortValue::Summary(a), SortValue::Summary(b)) => a.cmp(b),
+            (SortValue::Relevance(a), SortValue::Relevance(b)) => a.total_cmp(b),
+            (a, b) => a.rank().cmp(&b.rank()),
+        }
+    }
+}
+
+impl Sort {
+    /// Position of a record sorted this way
+    pub(crate) fn key(&self, record: &Record) -> PageKey {
+        let value = match self.field {
+            SortField::Created => SortValue::None,
+            SortField::State => SortValue::State(record.state as i32),
+            SortField::Summary => SortValue::Summary(record.summary.clone()),
+            SortField::Relevance => SortValue::Relevance(record.score.unwrap_or_default()),
+        };
+
+        PageKey {
+            field: self.field,
+            descending: self.descending,
+            value,
+            created: record.created.unwrap_or_default(),
+            id: record.id.unwrap_or_else(|| ObjectId::from_bytes([0; 12])),
+        }
+    }
+
+    /// Order of two records sorted this way
+    pub(crate) fn compare(&self, a: &Record, b: &Record) -> Ordering {
+        let ordering = self.key(a).position().cmp(&self.key(b).position());
+
+        if self.descending {
+            ordering.reverse()
+        } else {
+            ordering
+        }
+    }
+}
+
+impl PageKey {
+    fn position(&self) -> (&SortValue, i64, [u8; 12]) {
+        (&self.value, self.created, self.id.bytes())
+    }
+
+    /// Whether a record sorted the same way comes after this position
+    pub(crate) fn precedes(&self, record: &Record) -> bool {
+        let sort = Sort {
+            field: self.field,
+            descending: self.descending,
+        };
+        let ordering = self.position().cmp(&sort.key(record).position());
+
+        match self.descending {
+            false => ordering == Ordering::Less,
+            true => ordering == Ordering::Greater,
+        }
+    }
+
+    pub(crate) fn to_token(&self) -> String {
+        // a key is always serializable
+        let bytes = bson::to_vec(self).unwrap_or_default();
+
+        URL_SAFE_NO_PAD.encode(bytes)
+    }
+
+    /// Key of a page token, the search must be sorted the same way
+    pub(crate) fn from_token(token: &str, sort: Sort) -> Result<Self, Error> {
+        let key: PageKey = URL_SAFE_NO_PAD
+            .decode(token)
+            .ok()
+            .and_then(|bytes| bson::from_slice(&bytes).ok())
+            .ok_or_else(|| Error::invalid_argument("page_token", "malformed token"))?;
+
+        if key.field != sort.field || key.descending != sort.descending {
+            return Err(Error::invalid_argument(
+                "page_token",
+                "the token is for another sort",
+            ));
+        }
+
+        let valid = matches!(
+            (&key.field, &key.value),
+            (SortField::Created, SortValue::None)
+                | (SortField::State, SortValue::State(_))
+                | (SortField::Summary, SortValue::Summary(_))
+                | (SortField::Relevance, SortValue::Relevance(_))
+        );
+        if !valid {
+            return Err(Error::invalid_argument("page_token", "malformed token"));
+        }
+
+        Ok(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio_stream::StreamExt;
+
+    use super::*;
+    use crate::{
+        chain::Chain,
+        mongodb::RecordState,
+        storage::{Memory, Origin, SearchQuery, Storage},
+    };
+
+    fn record(summary: &str, created: i64, id: u8) -> Record {
+        Record {
+            id: Some(ObjectId::from_bytes([id; 12])),
+            api_version: 1,
+            created: Some(created),
+            summary: summary.to_owned(),
+            traces: None,
+            state: RecordState::Draft,
+            transitioned: None,
+            history: vec![],
+            seal: None,
+            attachments: vec![],
+            score: None,
+        }
+    }
+
+    fn sort(field: SortField, descending: bool) -> Sort {
+        Sort { field, descending }
+    }
+
+    #[test]
+    fn token_round_trip() {
+        let sort = sort(SortField::Summary, true);
+        let key = sort.key(&record("summary", 10, 1));
+
+        let parsed = PageKey::from_token(&key.to_token(), sort).expect("token parsed");
+
+        assert_eq!(parsed, key);
+    }
+
+    #[test]
+    fn token_of_another_sort_is_rejected() {
+        let token = sort(SortField::Summary, false)
+            .key(&record("summary", 10, 1))
+            .to_token();
+
+        for other in [
+            sort(SortField::Summary, true),
+            sort(SortField::Created, false),
+        ] {
+            let e = PageKey::from_token(&token, other).expect_err("token parsed");
+            assert!(matches!(e, Error::InvalidArgument { .. }), "{:?}", e);
+        }
+
+        let e = PageKey::from_token("not a token", sort(SortField::Created, false))
+            .expect_err("token parsed");
+        assert!(matches!(e, Error::InvalidArgument { .. }), "{:?}", e);
+    }
+
+    #[test]
+    fn ties_are_broken_by_creation_then_id() {
+        let first = record("same", 10, 1);
+        let second = record("same", 10, 2);
+        let third = record("same", 11, 0);
+
+        let ascending = sort(SortField::Summary, false);
+        assert_eq!(ascending.compare(&first, &second), Ordering::Less);
+        assert_eq!(ascending.compare(&second, &third), Ordering::Less);
+        let key = ascending.key(&first);
+        assert!(key.precedes(&second));
+        assert!(key.precedes(&third));
+        assert!(!key.precedes(&first));
+
+        let descending = sort(SortField::Summary, true);
+        assert_eq!(descending.compare(&third, &second), Ordering::Less);
+        assert_eq!(descending.compare(&second, &first), Ordering::Less);
+        let key = descending.key(&third);
+        assert!(key.precedes(&second));
+        assert!(key.precedes(&first));
+        assert!(!key.precedes(&third));
+    }
+
+    #[tokio::test]
+    async fn pages_list_every_record_once() {
+        let db = Arc::new(Memory::new(Chain::new(b"")));
+        let origin = Origin {
+            caller: "test".to_owned(),
+            rpc: "Test",
+        };
+
+        // duplicated summaries, created in the same second
+        let mut ids = vec![];
+        for summary in ["b", "a", "b", "c", "a", "b", "a"] {
+            let id = db
+                .insert_draft(summary.to_owned(), origin.clone())
+                .await
+                .expect("draft created");
+            ids.push(id);
+        }
+
+        for descending in [false, true] {
+            let sort = sort(SortField::Summary, descending);
+            let mut paged = vec![];
+            let mut after = None;
+
+            loop {
+                let query = SearchQuery {
+                    states: vec![RecordState::Draft],
+                    sort,
+                    after: after.take(),
+                    limit: Some(3),
+                    ..Default::default()
+                };
+                let page: Vec<Record> = db
+                    .search(query)
+                    .await
+                    .expect("searched")
+                    .map(|record| record.expect("record"))
+                    .collect()
+                    .await;
+
+                let Some(last) = page.last() else {
+                    break;
+                };
+                after = Some(
+                    PageKey::from_token(&sort.key(last).to_token(), sort).expect("token parsed"),
+                );
+                paged.extend(page);
+            }
+
+            let summaries: String = paged.iter().map(|record| record.summary.as_str()).collect();
+            let expected = if descending { "cbbbaaa" } else { "aaabbbc" };
+            assert_eq!(summaries, expected);
+
+            let mut paged_ids: Vec<_> = paged.iter().filter_map(|record| record.id).collect();
+            paged_ids.sort();
+            let mut expected_ids = ids.clone();
+            expected_ids.sort();
+            assert_eq!(paged_ids, expected_ids);
+        }
+    }
+}
@@ -0,0 +1,281 @@
+//! TLS
+//!
+//! The server certificate and the client CA bundle are reloaded without restart, on SIGHUP or
+//! when their files are modified. A new certificate is used by new connections only, the open
+//! ones (eg: `Watch` streams) are kept.
+
+use std::{
+    error::Error as StdError,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use chrono::{DateTime, Utc};
+use futures_util::stream;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    signal,
+    sync::mpsc,
+    time::{self, MissedTickBehavior},
+};
+use tokio_rustls::{
+    rustls::{server::WebPkiClientVerifier, RootCertStore, ServerConfig},
+    server::TlsStream,
+    TlsAcceptor,
+};
+use tokio_stream::{wrappers::ReceiverStream, Stream, StreamExt};
+use x509_parser::{certificate::X509Certificate, prelude::FromDer};
+
+use crate::config::ServiceConfig;
+
+type BoxError = Box<dyn StdError + Send + Sync>;
+
+/// h2 alpn, grpc requires http/2
+const ALPN_H2: &[u8] = b"h2";
+/// Time given to a client to complete the handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Wait after a failed accept (eg: too many open files), not to spin on the error
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+/// A certificate expiring sooner is logged as a warning
+const EXPIRY_WARNING_DAYS: i64 = 14;
+
+pub(crate) struct Tls {
+    files: Files,
+    /// Interval of the files checks in seconds, 0 if disabled
+    reload: u64,
+    config: RwLock<Arc<ServerConfig>>,
+    /// Modification times of the files when last checked
+    modified: Mutex<Vec<Option<SystemTime>>>,
+}
+
+impl Tls {
+    /// Load the certificate, its key and the client CA bundle of the configuration
+    pub(crate) async fn load(service: &ServiceConfig) -> Result<Self, Box<dyn StdError>> {
+        let files = Files {
+            cert: service.tls_cert.clone(),
+            key: service.tls_key.clone(),
+            client_ca: service.client_ca.clone(),
+            client_auth_optional: service.client_auth_optional,
+        };
+
+        let modified = files.modification_times().await;
+        let config = files
+            .server_config()
+            .await
+            .map_err(|e| e as Box<dyn StdError>)?;
+
+        if let Some(client_ca) = &files.client_ca {
+            tracing::info!(
+                "use client certificates: {} (optional: {})",
+                client_ca,
+                files.client_auth_optional
+            );
+        }
+
+        Ok(Self {
+            files,
+            reload: service.tls_reload,
+            config: RwLock::new(Arc::new(config)),
+            modified: Mutex::new(modified),
+        })
+    }
+
+    /// Reload the files on SIGHUP, or once modified, forever
+    ///
+    /// A failed reload is logged, the previous configuration is kept.
+    pub(crate) async fn run(self: Arc<Self>) {
+        let mut hangups = hangups();
+
+        let mut interval = time::interval(Duration::from_secs(self.reload.max(1)));
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // the first tick is immediate, the files are already loaded
+        interval.tick().await;
+
+        loop {
+            tokio::select! {
+                Some(()) = hangups.next() => {
+                    tracing::info!("SIGHUP received, reloading tls");
+                }
+                _ = interval.tick(), if self.reload > 0 => {
+                    let modified = self.files.modification_times().await;
+                    let mut previous = self.modified.lock().expect("tls lock poisoned");
+                    if modified == *previous {
+                        continue;
+                    }
+                    // a file failing to load isn't retried before its next modification
+                    *previous = modified;
+                    tracing::info!("tls files modified, reloading");
+                }
+            }
+
+            if let Err(e) = self.reload().await {
+                tracing::error!("tls reload failed: {}", e);
+            }
+        }
+    }
+
+    /// Connections of a listener, once their handshake succeeded with the current configuration
+    pub(crate) fn incoming(
+        self: Arc<Self>,
+        listener: TcpListener,
+    ) -> impl Stream<Item = Result<TlsStream<TcpStream>, io::Error>> {
+        let (tx, rx) = mpsc::channel(64);
+
+        tokio::spawn(async move {
+            while !tx.is_closed() {
+                let (stream, peer) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        tracing::warn!("accept failed: {}", e);
+                        time::sleep(ACCEPT_RETRY_DELAY).await;
+                        continue;
+                    }
+                };
+
+                let acceptor = TlsAcceptor::from(self.current());
+                let tx = tx.clone();
+
+                // a slow handshake doesn't hold the others
+                tokio::spawn(async move {
+                    match time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => {
+                            let _ = tx.send(Ok(stream)).await;
+                        }
+                        Ok(Err(e)) => tracing::debug!("tls handshake with {} failed: {}", peer, e),
+                        Err(_) => tracing::debug!("tls handshake with {} timed out", peer),
+                    }
+                });
+            }
+        });
+
+        ReceiverStream::new(rx)
+    }
+
+    fn current(&self) -> Arc<ServerConfig> {
+        self.config.read().expect("tls lock poisoned").clone()
+    }
+
+    /// Replace the configuration used by new connections
+    async fn reload(&self) -> Result<(), BoxError> {
+        let config = self.files.server_config().await?;
+        *self.config.write().expect("tls lock poisoned") = Arc::new(config);
+
+        Ok(())
+    }
+}
+
+/// Files of the TLS configuration
+struct Files {
+    cert: String,
+    key: String,
+    client_ca: Option<String>,
+    client_auth_optional: bool,
+}
+
+impl Files {
+    /// Server configuration read from the files
+    async fn server_config(&self) -> Result<ServerConfig, BoxError> {
+        let cert = tokio::fs::read(&self.cert).await?;
+        let key = tokio::fs::read(&self.key).await?;
+
+        let certs = rustls_pemfile::certs(&mut cert.as_slice()).collect::<Result<Vec<_>, _>>()?;
+        let key = rustls_pemfile::private_key(&mut key.as_slice())?
+            .ok_or_else(|| format!("no private key in {}", self.key))?;
+        let leaf = certs
+            .first()
+            .ok_or_else(|| format!("no certificate in {}", self.cert))?;
+        log_expiry(leaf)?;
+
+        let builder = ServerConfig::builder();
+        let builder = match &self.client_ca {
+            None => builder.with_no_client_auth(),
+            Some(client_ca) => {
+                let ca = tokio::fs::read(client_ca).await?;
+
+                let mut roots = RootCertStore::empty();
+                for cert in rustls_pemfile::certs(&mut ca.as_slice()) {
+                    roots.add(cert?)?;
+                }
+                if roots.is_empty() {
+                    return Err(format!("no certificate in {}", client_ca).into());
+                }
+
+                let verifier = WebPkiClientVerifier::builder(Arc::new(roots));
+                let verifier = if self.client_auth_optional {
+                    verifier.allow_unauthenticated()
+                } else {
+                    verifier
+                };
+
+                builder.with_client_cert_verifier(verifier.build()?)
+            }
+        };
+
+        let mut config = builder.with_single_cert(certs, key)?;
+        config.alpn_protocols.push(ALPN_H2.into());
+
+        Ok(config)
+    }
+
+    /// Modification times of the files, following symbolic links (eg: mounted secrets)
+    async fn modification_times(&self) -> Vec<Option<SystemTime>> {
+        let mut times = vec![];
+
+        for path in [Some(&self.cert), Some(&self.key), self.client_ca.as_ref()]
+            .into_iter()
+            .flatten()
+        {
+            let modified = tokio::fs::metadata(path)
+                .await
+                .and_then(|metadata| metadata.modified())
+                .ok();
+            times.push(modified);
+        }
+
+        times
+    }
+}
+
+/// SIGHUP signals received, none on other platforms
+fn hangups() -> Pin<Box<dyn Stream<Item = ()> + Send>> {
+    #[cfg(unix)]
+    match signal::unix::signal(signal::unix::SignalKind::hangup()) {
+        Ok(hangup) => {
+            return Box::pin(stream::unfold(hangup, |mut hangup| async move {
+                hangup.recv().await.map(|()| ((), hangup))
+            }))
+        }
+        Err(e) => tracing::warn!("tls reload on SIGHUP disabled: {}", e),
+    }
+
+    Box::pin(stream::pending())
+}
+
+/// Log the subject and the expiry of the server certificate
+fn log_expiry(cert: &[u8]) -> Result<(), BoxError> {
+    let (_, cert) = X509Certificate::from_der(cert)?;
+
+    let not_after = cert.validity().not_after.timestamp();
+    let expiry = DateTime::<Utc>::from_timestamp(not_after, 0).unwrap_or_default();
+    let days = (expiry - Utc::now()).num_days();
+
+    if days < EXPIRY_WARNING_DAYS {
+        tracing::warn!(
+            "tls certificate {} expires {} ({} days)",
+            cert.subject(),
+            expiry.to_rfc3339(),
+            days
+        );
+    } else {
+        tracing::info!(
+            "tls certificate {} expires {} ({} days)",
+            cert.subject(),
+            expiry.to_rfc3339(),
+            days
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,548 @@
+//! Register workflow
+//!
+//! Declarative description of a record life cycle.
+//!
+//! Each [Step] describes the state(s) required before it, the state reached after it,
+//! the field it writes and the guards checked on the request payload.
+//! All storage mutations go through [step] so the rules live in a single place.
+//!
+//! ```text
+//! Draft -> Created -> CollectClientInside -> CollectClientSignature -> CollectClientOutside -> CollectPqrsSignature
+//!       -> ReturnClientInside -> ReturnClientSignature -> ReturnClientOutside -> ReturnPqrsSignature -> Completed
+//! ```
+
+use core::fmt;
+
+use mongodb::bson::oid::ObjectId;
+
+use crate::mongodb::{Attachment, RecordState, SignatureTraceFor, Signer, TimeTraceFor};
+
+/// State of a new record
+pub(crate) const INITIAL: RecordState = RecordState::Draft;
+
+/// State sealing the hash chain of a record, no update can be done after
+pub(crate) const SEALED: RecordState = RecordState::Completed;
+
+/// States allowing a record to be deleted
+pub(crate) const DELETABLE: &[RecordState] = &[RecordState::Draft];
+
+/// States allowing documents to be attached to or removed from a record
+pub(crate) const ATTACHABLE: &[RecordState] = &[RecordState::Draft];
+
+/// Actions changing a record
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Action {
+    UpdateDraft,
+    UploadAttachment,
+    DeleteAttachment,
+    SubmitDraft,
+    CollectClientInside,
+    CollectClientSignature,
+    CollectClientOutside,
+    CollectPqrsSignature,
+    ReturnClientInside,
+    ReturnClientSignature,
+    ReturnClientOutside,
+    ReturnPqrsSignature,
+    Complete,
+}
+
+/// Trace written by a step
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum TraceField {
+    CollectedInside,
+    CollectedClient,
+    CollectedOutside,
+    CollectedPqrs,
+    ReturnedInside,
+    ReturnedClient,
+    ReturnedOutside,
+    ReturnedPqrs,
+}
+
+/// Record field written by a step
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Field {
+    Summary,
+    /// Server time when the step is applied
+    Created,
+    Trace(TraceField),
+    /// Documents attached to the record, written as a whole
+    Attachments,
+}
+
+/// Condition checked on the payload before a step is applied
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Guard {
+    /// The client time must be set
+    TimeSet,
+    /// The signer must be named
+    SignerNamed,
+    /// The signature must be set
+    SignatureSet,
+}
+
+/// Value provided with an action
+#[derive(Debug)]
+pub(crate) enum Payload {
+    None,
+    Summary(String),
+    Time(i64),
+    Signer(Signer),
+    /// Attachment added to the record
+    Attach(Attachment),
+    /// Id of the attachment removed from the record
+    Detach(ObjectId),
+}
+
+/// A transition of the workflow
+#[derive(Debug)]
+pub(crate) struct Step {
+    pub(crate) action: Action,
+    pub(crate) from: &'static [RecordState],
+    pub(crate) to: RecordState,
+    pub(crate) writes: Option<Field>,
+    pub(crate) guards: &'static [Guard],
+}
+
+/// Reason why a step can't be applied
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Violation {
+    /// The record is not in a state allowing the step
+    State {
+        current: RecordState,
+        expected: &'static [RecordState],
+    },
+    /// A guard is not satisfied by the payload
+    Guard(Guard),
+}
+
+const TIME_GUARDS: &[Guard] = &[Guard::TimeSet];
+const SIGNER_GUARDS: &[Guard] = &[Guard::SignerNamed, Guard::SignatureSet];
+
+/// The workflow
+pub(crate) static STEPS: [Step; 13] = [
+    Step {
+        action: Action::UpdateDraft,
+        from: &[RecordState::Draft],
+        to: RecordState::Draft,
+        writes: Some(Field::Summary),
+        guards: &[],
+    },
+    // Documents can't be attached nor removed once the draft is submitted
+    Step {
+        action: Action::UploadAttachment,
+        from: ATTACHABLE,
+        to: RecordState::Draft,
+        writes: Some(Field::Attachments),
+        guards: &[],
+    },
+    Step {
+        action: Action::DeleteAttachment,
+        from: ATTACHABLE,
+        to: RecordState::Draft,
+        writes: Some(Field::Attachments),
+        guards: &[],
+    },
+    Step {
+        action: Action::SubmitDraft,
+        from: &[RecordState::Draft],
+        to: RecordState::Created,
+        writes: Some(Field::Created),
+        guards: &[],
+    },
+    // Client can collect only after request created
+    Step {
+        action: Action::CollectClientInside,
+        from: &[RecordState::Created],
+        to: RecordState::CollectClientInside,
+        writes: Some(Field::Trace(TraceField::CollectedInside)),
+        guards: TIME_GUARDS,
+    },
+    // Client can sign only inside office
+    Step {
+        action: Action::CollectClientSignature,
+        from: &[RecordState::CollectClientInside],
+        to: RecordState::CollectClientSignature,
+        writes: Some(Field::Trace(TraceField::CollectedClient)),
+        guards: SIGNER_GUARDS,
+    },
+    // Client can go out after collect only after signature
+    Step {
+        action: Action::CollectClientOutside,
+        from: &[RecordState::CollectClientSignature],
+        to: RecordState::CollectClientOutside,
+        writes: Some(Field::Trace(TraceField::CollectedOutside)),
+        guards: TIME_GUARDS,
+    },
+    // PQRS can sign only after client go out
+    Step {
+        action: Action::CollectPqrsSignature,
+        from: &[RecordState::CollectClientOutside],
+        to: RecordState::CollectPqrsSignature,
+        writes: Some(Field::Trace(TraceField::CollectedPqrs)),
+        guards: SIGNER_GUARDS,
+    },
+    // Client can return products only after pqrs signature during collect
+    Step {
+        action: Action::ReturnClientInside,
+        from: &[RecordState::CollectPqrsSignature],
+        to: RecordState::ReturnClientInside,
+        writes: Some(Field::Trace(TraceField::ReturnedInside)),
+        guards: TIME_GUARDS,
+    },
+    // Client can sign only inside office
+    Step {
+        action: Action::ReturnClientSignature,
+        from: &[RecordState::ReturnClientInside],
+        to: RecordState::ReturnClientSignature,
+        writes: Some(Field::Trace(TraceField::ReturnedClient)),
+        guards: SIGNER_GUARDS,
+    },
+    // Client can go out after return only after signature
+    Step {
+        action: Action::ReturnClientOutside,
+        from: &[RecordState::ReturnClientSignature],
+        to: RecordState::ReturnClientOutside,
+        writes: Some(Field::Trace(TraceField::ReturnedOutside)),
+        guards: TIME_GUARDS,
+    },
+    // PQRS can sign only after client go out
+    Step {
+        action: Action::ReturnPqrsSignature,
+        from: &[RecordState::ReturnClientOutside],
+        to: RecordState::ReturnPqrsSignature,
+        writes: Some(Field::Trace(TraceField::ReturnedPqrs)),
+        guards: SIGNER_GUARDS,
+    },
+    // No update can be done after
+    Step {
+        action: Action::Complete,
+        from: &[RecordState::ReturnPqrsSignature],
+        to: RecordState::Completed,
+        writes: None,
+        guards: &[],
+    },
+];
+
+/// The step applied by an action
+pub(crate) fn step(action: Action) -> &'static Step {
+    STEPS
+        .iter()
+        .find(|step| step.action == action)
+        .expect("every action has a step")
+}
+
+impl Step {
+    /// Check the record is in a state allowing the step
+    pub(crate) fn check_state(&self, current: RecordState) -> Result<(), Violation> {
+        if self.from.contains(&current) {
+            Ok(())
+        } else {
+            Err(Violation::State {
+                current,
+                expected: self.from,
+            })
+        }
+    }
+
+    /// Whether the step moves the record to another state
+    pub(crate) fn changes_state(&self) -> bool {
+        !self.from.contains(&self.to)
+    }
+
+    /// Check the payload satisfies every guard of the step
+    pub(crate) fn check_guards(&self, payload: &Payload) -> Result<(), Violation> {
+        match self.guards.iter().find(|guard| !guard.holds(payload)) {
+            None => Ok(()),
+            Some(guard) => Err(Violation::Guard(*guard)),
+        }
+    }
+}
+
+impl Guard {
+    fn holds(&self, payload: &Payload) -> bool {
+        match (self, payload) {
+            (Guard::TimeSet, Payload::Time(time)) => *time > 0,
+            (Guard::SignerNamed, Payload::Signer(signer)) => !signer.name.is_empty(),
+            (Guard::SignatureSet, Payload::Signer(signer)) => !signer.signature.is_empty(),
+            _ => false,
+        }
+    }
+}
+
+impl TraceField {
+    /// Path of the trace in a record
+    pub(crate) fn path(&self) -> &'static str {
+        match self {
+            TraceField::CollectedInside => "traces.collected.inside",
+            TraceField::CollectedClient => "traces.collected.client",
+            TraceField::CollectedOutside => "traces.collected.outside",
+            TraceField::CollectedPqrs => "traces.collected.pqrs",
+            TraceField::ReturnedInside => "traces.returned.inside",
+            TraceField::ReturnedClient => "traces.returned.client",
+            TraceField::ReturnedOutside => "traces.returned.outside",
+            TraceField::ReturnedPqrs => "traces.returned.pqrs",
+        }
+    }
+}
+
+impl Field {
+    /// Path of the field in a record
+    pub(crate) fn path(&self) -> &'static str {
+        match self {
+            Field::Summary => "summary",
+            Field::Created => "created",
+            Field::Trace(trace) => trace.path(),
+            Field::Attachments => "attachments",
+        }
+    }
+}
+
+impl From<TimeTraceFor> for Action {
+    fn from(value: TimeTraceFor) -> Self {
+        match value {
+            TimeTraceFor::ClientInsideForCollect => Action::CollectClientInside,
+            TimeTraceFor::ClientOutsideAfterCollect => Action::CollectClientOutside,
+            TimeTraceFor::ClientInsideForReturn => Action::ReturnClientInside,
+            TimeTraceFor::ClientOutsideAfterReturn => Action::ReturnClientOutside,
+        }
+    }
+}
+
+impl From<SignatureTraceFor> for Action {
+    fn from(value: SignatureTraceFor) -> Self {
+        match value {
+            SignatureTraceFor::CollectByClient => Action::CollectClientSignature,
+            SignatureTraceFor::CollectConfirmedByPqrs => Action::CollectPqrsSignature,
+            SignatureTraceFor::ReturnByClient => Action::ReturnClientSignature,
+            SignatureTraceFor::ReturnConfirmedByPqrs => Action::ReturnPqrsSignature,
+        }
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::State { current, expected } => {
+                write!(f, "record is {:?}, expected one of {:?}", current, expected)
+            }
+            Violation::Guard(guard) => write!(f, "guard {:?} not satisfied", guard),
+        }
+    }
+}
+
+/// Render the workflow as a graphviz DOT graph
+///
+/// ```bash
+/// encelade-register-backend --workflow-dot | dot -Tsvg > workflow.svg
+/// ```
+pub(crate) fn to_dot() -> String {
+    let mut dot = String::from(
+        "digraph register {\n    rankdir=LR;\n    new [shape=point];\n    deleted [shape=point];\n",
+    );
+
+    dot.push_str(&format!("    new -> {:?} [label=\"NewDraft\"];\n", INITIAL));
+
+    for state in DELETABLE {
+        dot.push_str(&format!(
+            "    {:?} -> deleted [label=\"DeleteDraft\"];\n",
+            state
+        ));
+    }
+
+    for step in STEPS.iter() {
+        let mut label = format!("{:?}", step.action);
+
+        if let Some(field) = step.writes {
+            label.push_str(&format!("\\n{}", field.path()));
+        }
+        if !step.guards.is_empty() {
+            label.push_str(&format!("\\n{:?}", step.guards));
+        }
+
+        for from in step.from {
+            dot.push_str(&format!(
+                "    {:?} -> {:?} [label=\"{}\"];\n",
+                from, step.to, label
+            ));
+        }
+    }
+
+    dot.push_str("}\n");
+    dot
+}
+
+#[cfg(test)]
+mod tests {
+    use num_traits::FromPrimitive;
+
+    use super::*;
+
+    const ACTIONS: [Action; 13] = [
+        Action::UpdateDraft,
+        Action::UploadAttachment,
+        Action::DeleteAttachment,
+        Action::SubmitDraft,
+        Action::CollectClientInside,
+        Action::CollectClientSignature,
+        Action::CollectClientOutside,
+        Action::CollectPqrsSignature,
+        Action::ReturnClientInside,
+        Action::ReturnClientSignature,
+        Action::ReturnClientOutside,
+        Action::ReturnPqrsSignature,
+        Action::Complete,
+    ];
+
+    fn states() -> Vec<RecordState> {
+        (0..=11).filter_map(RecordState::from_i32).collect()
+    }
+
+    fn payload(step: &Step) -> Payload {
+        let id = ObjectId::new();
+
+        match step.writes {
+            Some(Field::Summary) => Payload::Summary("summary".to_owned()),
+            Some(Field::Trace(
+                TraceField::CollectedInside
+                | TraceField::CollectedOutside
+                | TraceField::ReturnedInside
+                | TraceField::ReturnedOutside,
+            )) => Payload::Time(1_713_906_000),
+            Some(Field::Trace(_)) => Payload::Signer(Signer {
+                name: "name".to_owned(),
+                signature: "signature".to_owned(),
+                signed: None,
+                image: None,
+                identity: None,
+            }),
+            Some(Field::Attachments) if step.action == Action::DeleteAttachment => {
+                Payload::Detach(id)
+            }
+            Some(Field::Attachments) => Payload::Attach(Attachment {
+                id,
+                filename: "scan.pdf".to_owned(),
+                content_type: "application/pdf".to_owned(),
+                length: 0,
+                sha256: String::new(),
+                uploaded: 1_713_906_000,
+            }),
+            Some(Field::Created) | None => Payload::None,
+        }
+    }
+
+    #[test]
+    fn every_action_has_a_single_step() {
+        for action in ACTIONS {
+            let steps = STEPS.iter().filter(|step| step.action == action).count();
+
+            assert_eq!(steps, 1, "{:?}", action);
+        }
+    }
+
+    #[test]
+    fn legal_and_illegal_transitions() {
+        for step in STEPS.iter() {
+            for state in states() {
+                let result = step.check_state(state);
+
+                if step.from.contains(&state) {
+                    assert_eq!(result, Ok(()), "{:?} from {:?}", step.action, state);
+                } else {
+                    assert_eq!(
+                        result,
+                        Err(Violation::State {
+                            current: state,
+                            expected: step.from
+                        }),
+                        "{:?} from {:?}",
+                        step.action,
+                        state
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn nominal_path_reaches_completed() {
+        let mut state = INITIAL;
+
+        for action in ACTIONS {
+            let step = step(action);
+
+            assert_eq!(step.check_state(state), Ok(()), "{:?}", action);
+            assert_eq!(step.check_guards(&payload(step)), Ok(()), "{:?}", action);
+            state = step.to;
+        }
+
+        assert_eq!(state, RecordState::Completed);
+    }
+
+    #[test]
+    fn completed_is_final() {
+        for step in STEPS.iter() {
+            assert!(!step.from.contains(&RecordState::Completed));
+        }
+        assert!(!DELETABLE.contains(&RecordState::Completed));
+    }
+
+    #[test]
+    fn only_drafts_are_deletable() {
+        assert_eq!(DELETABLE, &[RecordState::Draft]);
+    }
+
+    #[test]
+    fn time_guards() {
+        let step = step(Action::CollectClientInside);
+
+        assert_eq!(
+            step.check_guards(&Payload::Time(0)),
+            Err(Violation::Guard(Guard::TimeSet))
+        );
+        assert_eq!(step.check_guards(&Payload::Time(1)), Ok(()));
+    }
+
+    #[test]
+    fn signer_guards() {
+        let step = step(Action::ReturnPqrsSignature);
+        let signer = |name: &str, signature: &str| {
+            Payload::Signer(Signer {
+                name: name.to_owned(),
+                signature: signature.to_owned(),
+                signed: None,
+                image: None,
+                identity: None,
+            })
+        };
+
+        assert_eq!(
+            step.check_guards(&signer("", "ps")),
+            Err(Violation::Guard(Guard::SignerNamed))
+        );
+        assert_eq!(
+            step.check_guards(&signer("pqrs", "")),
+            Err(Violation::Guard(Guard::SignatureSet))
+        );
+        assert_eq!(step.check_guards(&signer("pqrs", "ps")), Ok(()));
+    }
+
+    #[test]
+    fn dot_contains_every_transition() {
+        let dot = to_dot();
+
+        assert!(dot.starts_with("digraph register {"));
+        assert!(dot.contains("new -> Draft"));
+        assert!(dot.contains("Draft -> deleted"));
+
+        for step in STEPS.iter() {
+            for from in step.from {
+                assert!(dot.contains(&format!(
+                    "{:?} -> {:?} [label=\"{:?}",
+                    from, step.to, step.action
+                )));
+            }
+        }
+    }
+}